@@ -0,0 +1,19 @@
+use waitpidx::netlink::{ProcEvent, ProcEventKinds, ProcEventStream};
+
+fn main() {
+    let stream =
+        ProcEventStream::new(ProcEventKinds::all()).expect("subscribe failed, root required");
+
+    for event in stream {
+        match event {
+            Ok(ProcEvent::Fork(e)) => println!("fork   {:?} -> {:?}", e.parent_tgid, e.child_pid),
+            Ok(ProcEvent::Exec(e)) => println!("exec   {:?}", e.pid),
+            Ok(ProcEvent::Comm(e)) => {
+                println!("comm   {:?} {}", e.pid, String::from_utf8_lossy(e.name()))
+            }
+            Ok(ProcEvent::Exit(e)) => println!("exit   {:?} {:?}", e.pid, e.info().status()),
+            Ok(e) => println!("other  {e:?}"),
+            Err(e) => eprintln!("error: {e:?}"),
+        }
+    }
+}
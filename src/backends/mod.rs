@@ -6,11 +6,13 @@ use std::{io::Result, time::Duration};
 
 use rustix::process::Pid;
 
+use crate::ExitInfo;
+
 pub(crate) trait Backend {
-    fn waitpid(&self, pid: Pid, timeout: Option<Duration>) -> Result<()>;
+    fn waitpid(&self, pid: Pid, timeout: Option<Duration>) -> Result<Option<ExitInfo>>;
 }
 
 #[cfg(feature = "async")]
 pub(crate) trait AsyncBackend {
-    async fn waitpid(&self, pid: Pid) -> Result<()>;
+    async fn waitpid(&self, pid: Pid) -> Result<Option<ExitInfo>>;
 }
@@ -1,75 +1,366 @@
 /// Async netlink waiter
 use std::{
-    collections::HashMap,
+    future::Future,
     io::{Error, ErrorKind, Result},
-    iter,
-    sync::{Arc, Mutex},
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll},
 };
 
+use futures_core::Stream;
 use rustix::process::Pid;
 
-use super::{binding::NL_CONNECTOR_MAX_MSG_SIZE, connection::NetlinkConnection};
-use crate::{backends::AsyncBackend, utils};
+use super::{
+    connection::{AsyncNetlinkConnection, NetlinkConnection},
+    event::{ExitEvent, ProcEvent, ProcEventKinds},
+    interest::{self, NotifyExit, NotifyThreadExit},
+};
+use crate::{
+    backends::AsyncBackend,
+    utils::{self, procfs},
+    ExitInfo,
+};
+
+/// Backend used by [`AsyncNetlinkBackend::shared`]
+static SHARED: Mutex<Weak<AsyncNetlinkBackend>> = Mutex::new(Weak::new());
+
+type AsyncExitNotifier = tokio::sync::oneshot::Sender<Option<ExitInfo>>;
+type AsyncThreadExitNotifier = tokio::sync::mpsc::UnboundedSender<ExitEvent>;
+
+type AsyncInterestGroup = interest::InterestGroup<AsyncExitNotifier, AsyncThreadExitNotifier>;
+
+impl NotifyExit for AsyncExitNotifier {
+    fn notify(self, info: Option<ExitInfo>) {
+        let _ = self.send(info); // don't care if the receiver is dropped
+    }
+}
+
+impl NotifyThreadExit for AsyncThreadExitNotifier {
+    fn notify(&self, event: ExitEvent) {
+        let _ = self.send(event); // as above
+    }
+}
+
+/// Removes its waiter from the backend when dropped
+#[derive(Debug)]
+struct AsyncRegistration {
+    inner: Weak<AsyncNetlinkBackendInner>,
+    pid: Pid,
+    id: u64,
+}
+
+impl Drop for AsyncRegistration {
+    // the filter is updated on the blocking pool, so dropping never blocks
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.upgrade() {
+            inner.unregister(self.pid, self.id);
+        }
+    }
+}
+
+/// Pending exit of a process, see [`AsyncNetlinkBackend::interest`].
+///
+/// Resolves to `None` if the exit event was lost, fails with [`ErrorKind::BrokenPipe`] if the
+/// backend stopped. Dropping it stops watching the process, unless someone else does.
+#[derive(Debug)]
+pub struct AsyncExitReceiver {
+    rx: tokio::sync::oneshot::Receiver<Option<ExitInfo>>,
+    registration: AsyncRegistration,
+}
+
+impl AsyncExitReceiver {
+    #[inline]
+    pub fn pid(&self) -> Pid {
+        self.registration.pid
+    }
+}
+
+impl Future for AsyncExitReceiver {
+    type Output = Result<Option<ExitInfo>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx)
+            .poll(cx)
+            .map_err(|_| ErrorKind::BrokenPipe.into())
+    }
+}
+
+/// Exits of the threads of a process, see [`AsyncNetlinkBackend::interest_threads`].
+///
+/// Dropping it stops watching the process, unless someone else does.
+#[derive(Debug)]
+pub struct AsyncThreadExitReceiver {
+    rx: tokio::sync::mpsc::UnboundedReceiver<ExitEvent>,
+    registration: AsyncRegistration,
+}
+
+impl AsyncThreadExitReceiver {
+    #[inline]
+    pub fn pid(&self) -> Pid {
+        self.registration.pid
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<ExitEvent>> {
+        self.rx.poll_recv(cx)
+    }
 
-type AsyncExitNotifier = tokio::sync::oneshot::Sender<()>;
-type AsyncExitReceiver = tokio::sync::oneshot::Receiver<()>;
+    /// Wait for the next thread to exit, `None` once the whole thread group is gone.
+    pub async fn recv(&mut self) -> Option<ExitEvent> {
+        self.rx.recv().await
+    }
+}
+
+impl Stream for AsyncThreadExitReceiver {
+    type Item = ExitEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
 
 #[derive(Debug)]
 struct AsyncNetlinkBackendInner {
-    netlink: NetlinkConnection,
-    interest: Mutex<HashMap<Pid, Vec<AsyncExitNotifier>>>,
+    netlink: AsyncNetlinkConnection,
+    interest: Mutex<AsyncInterestGroup>,
+    /// Notified whenever an update of the filter finished
+    applied: tokio::sync::Notify,
+    /// Runtime of the event loop, the filter is updated on its blocking pool
+    runtime: tokio::runtime::Handle,
 }
 
 impl AsyncNetlinkBackendInner {
     fn new() -> Result<Arc<Self>> {
-        let netlink = NetlinkConnection::new()?;
+        // started by the first update, LISTEN waits for the acknowledgement
+        let netlink = NetlinkConnection::new(ProcEventKinds::EXIT)?;
         netlink.interest(Some(&[]))?;
-        netlink.start()?;
 
         Ok(Arc::new(Self {
-            netlink,
+            netlink: AsyncNetlinkConnection::new(netlink)?,
             interest: Default::default(),
+            applied: tokio::sync::Notify::new(),
+            runtime: tokio::runtime::Handle::current(),
         }))
     }
 
-    async fn interest(&self, pid: Pid) -> Result<AsyncExitReceiver> {
+    async fn interest(self: &Arc<Self>, pid: Pid) -> Result<AsyncExitReceiver> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let (registration, generation) = {
+            let mut interest_group = self.interest.lock().unwrap_or_else(|x| x.into_inner());
+            let registration = self.register(&mut interest_group, pid)?;
+
+            interest_group.update(pid, |x| {
+                let waiters = x.exits.entry(pid).or_default();
+                waiters.insert(registration.id, tx);
+            });
+            (registration, interest_group.generation)
+        };
+
+        if !self.watch(pid, generation).await? {
+            // dropped from the filter by the next update
+            let mut interest_group = self.interest.lock().unwrap_or_else(|x| x.into_inner());
+            if let Some(tx) = interest_group.take_exit(pid, registration.id) {
+                let _ = tx.send(None);
+            }
+        }
+
+        Ok(AsyncExitReceiver { rx, registration })
+    }
+
+    async fn interest_threads(self: &Arc<Self>, pid: Pid) -> Result<AsyncThreadExitReceiver> {
+        // nothing to report if the process is gone, the channel is closed with the sender then
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let (registration, generation) = {
+            let mut interest_group = self.interest.lock().unwrap_or_else(|x| x.into_inner());
+            let registration = self.register(&mut interest_group, pid)?;
+
+            interest_group.update(pid, |x| {
+                let waiters = x.threads.entry(pid).or_default();
+                waiters.insert(registration.id, tx);
+            });
+            (registration, interest_group.generation)
+        };
+
+        if !self.watch(pid, generation).await? {
+            let mut interest_group = self.interest.lock().unwrap_or_else(|x| x.into_inner());
+            interest_group.remove(pid, registration.id);
+        }
+
+        Ok(AsyncThreadExitReceiver { rx, registration })
+    }
+
+    fn register(
+        self: &Arc<Self>,
+        interest_group: &mut AsyncInterestGroup,
+        pid: Pid,
+    ) -> Result<AsyncRegistration> {
+        if interest_group.closed {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+
+        Ok(AsyncRegistration {
+            inner: Arc::downgrade(self),
+            pid,
+            id: interest_group.next_id(),
+        })
+    }
+
+    /// Wait until the filter passes events of `pid`, `false` if the process has exited
+    async fn watch(self: &Arc<Self>, pid: Pid, generation: u64) -> Result<bool> {
+        self.apply(generation).await?;
+
+        // the exit event was dropped if the process exited before the filter passed it
+        Ok(procfs::is_running(pid))
+    }
+
+    /// Wait until the filter passes the pids of `generation` or a later one, the update fails
+    /// for everyone waiting for it.
+    async fn apply(self: &Arc<Self>, generation: u64) -> Result<()> {
+        loop {
+            // guaranteed to be woken by any notification after its creation
+            let applied = self.applied.notified();
+
+            {
+                let mut interest_group = self.interest.lock().unwrap_or_else(|x| x.into_inner());
+                if interest_group.applied >= generation {
+                    return Ok(());
+                }
+                if interest_group.closed {
+                    return Err(ErrorKind::BrokenPipe.into());
+                }
+                match &interest_group.failed {
+                    Some((target, e)) if *target >= generation => {
+                        return Err(Error::new(e.kind(), e.to_string()));
+                    }
+                    _ => self.schedule(&mut interest_group),
+                }
+            }
+
+            applied.await;
+        }
+    }
+
+    /// Update the filter on the blocking pool unless an update is running, which applies every
+    /// change made meanwhile
+    fn schedule(self: &Arc<Self>, interest_group: &mut AsyncInterestGroup) {
+        if interest_group.applying
+            || interest_group.closed
+            || interest_group.applied >= interest_group.generation
+        {
+            return;
+        }
+
+        interest_group.applying = true;
+        let inner = self.clone();
+        // may not finish if the runtime shuts down, nothing is waited for then
+        drop(self.runtime.spawn_blocking(move || inner.update()));
+    }
+
+    /// Rebuild the filter until it passes the latest generation, or fails.
+    ///
+    /// Blocks while the kernel acknowledges LISTEN of the first update, or of new sockets.
+    fn update(&self) {
         let mut interest_group = self.interest.lock().unwrap_or_else(|x| x.into_inner());
 
-        let keys = interest_group
-            .keys()
-            .copied()
-            .chain(iter::once(pid))
-            .collect::<Vec<_>>();
+        while interest_group.applied < interest_group.generation {
+            let target = interest_group.generation;
+            let update = interest_group.take_update();
+            drop(interest_group);
 
-        self.netlink.interest(Some(keys.as_slice()))?;
+            let result = self
+                .netlink
+                .start()
+                .and_then(|_| self.netlink.update(&update));
 
-        let (tx, rx) = tokio::sync::oneshot::channel();
-        interest_group.entry(pid).or_default().push(tx);
-        Ok(rx)
+            interest_group = self.interest.lock().unwrap_or_else(|x| x.into_inner());
+            match result {
+                Ok(()) => interest_group.applied = target,
+                Err(e) => {
+                    // the changes are lost
+                    interest_group.full = true;
+                    interest_group.failed = Some((target, e));
+                    break;
+                }
+            }
+        }
+
+        interest_group.applying = false;
+        self.applied.notify_waiters();
+    }
+
+    fn unregister(self: &Arc<Self>, pid: Pid, id: u64) {
+        let mut interest_group = self.interest.lock().unwrap_or_else(|x| x.into_inner());
+
+        if interest_group.remove(pid, id) {
+            self.schedule(&mut interest_group);
+        }
     }
 
-    async fn handle_events(&self) -> Result<()> {
-        let mut buf = [0u8; NL_CONNECTOR_MAX_MSG_SIZE];
+    async fn handle_events(self: &Arc<Self>) -> Result<()> {
+        let mut lost = false;
 
         loop {
-            let pid = self.netlink.read_event_async(&mut buf).await?;
+            // the kernel doesn't report another overflow before the queue is drained,
+            // so resync only after that
+            let events = match lost {
+                true => self.netlink.try_read_events(),
+                false => self.netlink.read_events().await,
+            };
+
+            let events = match events {
+                Ok(events) => events,
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    lost = true;
+                    continue;
+                }
+                Err(e) if lost && e.kind() == ErrorKind::WouldBlock => {
+                    lost = false;
+                    self.resync();
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
 
             let mut interest_group = self.interest.lock().unwrap_or_else(|x| x.into_inner());
-            if let Some(notifiers) = interest_group.remove(&pid) {
-                for notifier in notifiers {
-                    let _ = notifier.send(()); // don't care if the receiver is dropped
+
+            for event in events {
+                if let ProcEvent::Exit(exit) = event {
+                    interest_group.dispatch(exit);
                 }
             }
 
-            let keys = interest_group.keys().copied().collect::<Vec<_>>();
-            match self.netlink.interest(Some(&keys)) {
-                Ok(()) => (),
-                Err(_) => return Ok(()), // OwnedFd is dropped
-            }
+            // the filter is updated once for all exits of the batch, if any, exited pids
+            // only pass events which are ignored meanwhile
+            self.schedule(&mut interest_group);
         }
     }
+
+    /// Events were lost, complete the waiters of processes that are gone meanwhile
+    fn resync(self: &Arc<Self>) {
+        let mut interest_group = self.interest.lock().unwrap_or_else(|x| x.into_inner());
+        interest_group.resync();
+
+        self.schedule(&mut interest_group);
+    }
+
+    /// Called once the event loop stopped, waiters are woken with [`ErrorKind::BrokenPipe`]
+    fn close(&self) {
+        let mut interest_group = self.interest.lock().unwrap_or_else(|x| x.into_inner());
+        interest_group.close();
+        self.applied.notify_waiters();
+    }
+}
+
+struct CloseOnDrop(Arc<AsyncNetlinkBackendInner>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.close();
+    }
 }
 
+/// Async version of [`NetlinkBackend`](super::NetlinkBackend).
+///
+/// Events are received by a tokio task, which lives as long as the backend.
 #[derive(Debug)]
 pub struct AsyncNetlinkBackend {
     inner: Arc<AsyncNetlinkBackendInner>,
@@ -83,10 +374,11 @@ impl AsyncNetlinkBackend {
         let h = tokio::spawn({
             let inner = inner.clone();
             async move {
-                match inner.handle_events().await {
-                    Ok(()) => { /* connection closed */ }
-                    Err(e) => panic!("{e:?}"),
-                }
+                // also closes when the task is aborted or its runtime shuts down
+                let _guard = CloseOnDrop(inner.clone());
+
+                // the result doesn't matter, waiters see a closed channel either way
+                let _ = inner.handle_events().await;
             }
         });
         let aborter = h.abort_handle();
@@ -94,9 +386,60 @@ impl AsyncNetlinkBackend {
         Ok(Self { inner, aborter })
     }
 
+    /// Backend shared by every caller in the process, used by
+    /// [`waitpid_async`](crate::waitpid_async). Must be called within a tokio runtime.
+    ///
+    /// Created on first use, the socket and task are torn down once the last [`Arc`] is
+    /// dropped. It's created again if the runtime running the task has shut down.
+    pub fn shared() -> Result<Arc<Self>> {
+        let mut shared = SHARED.lock().unwrap_or_else(|x| x.into_inner());
+
+        if let Some(backend) = shared.upgrade().filter(|x| !x.aborter.is_finished()) {
+            return Ok(backend);
+        }
+
+        let backend = Arc::new(Self::new()?);
+        *shared = Arc::downgrade(&backend);
+        Ok(backend)
+    }
+
+    /// See [`NetlinkBackend::set_recv_buffer_size`](super::NetlinkBackend::set_recv_buffer_size).
+    pub fn set_recv_buffer_size(&self, size: usize) -> Result<()> {
+        self.inner.netlink.set_recv_buffer_size(size)
+    }
+
+    /// Number of times the socket overflowed and exit events were lost.
+    ///
+    /// Waiters of processes that exited meanwhile receive `None`.
+    pub fn overflows(&self) -> u64 {
+        self.inner.netlink.overflows()
+    }
+
+    /// Receive the [`ExitInfo`] once the whole thread group of `pid` is gone.
+    ///
+    /// Receives `None` right away if the process has already exited.
+    ///
+    /// Stops watching the process once every receiver of it is dropped.
     pub async fn interest(&self, pid: Pid) -> Result<AsyncExitReceiver> {
         self.inner.interest(pid).await
     }
+
+    /// Receive the [`ExitEvent`] of every thread of `pid`.
+    ///
+    /// The channel is closed after the exit of the last thread, or right away if the process
+    /// has already exited.
+    pub async fn interest_threads(&self, pid: Pid) -> Result<AsyncThreadExitReceiver> {
+        self.inner.interest_threads(pid).await
+    }
+
+    /// Wait for the process to exit, `None` if its exit event was lost.
+    pub async fn waitpid(&self, pid: Pid) -> Result<Option<ExitInfo>> {
+        if !utils::process_exists(pid) {
+            return Err(Error::from_raw_os_error(libc::ESRCH));
+        }
+
+        self.interest(pid).await?.await
+    }
 }
 
 impl Drop for AsyncNetlinkBackend {
@@ -107,15 +450,7 @@ impl Drop for AsyncNetlinkBackend {
 }
 
 impl AsyncBackend for AsyncNetlinkBackend {
-    async fn waitpid(&self, pid: Pid) -> Result<()> {
-        if !utils::process_exists(pid) {
-            return Err(Error::from_raw_os_error(libc::ESRCH));
-        }
-
-        let rx = self.interest(pid).await?;
-        match rx.await {
-            Ok(()) => Ok(()),
-            Err(_) => Err(ErrorKind::BrokenPipe.into()),
-        }
+    async fn waitpid(&self, pid: Pid) -> Result<Option<ExitInfo>> {
+        AsyncNetlinkBackend::waitpid(self, pid).await
     }
 }
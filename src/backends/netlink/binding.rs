@@ -1,17 +1,18 @@
 use std::mem::size_of;
 
-pub(super) use linux_raw_sys::netlink::{nlmsghdr, NLMSG_DONE};
+pub(super) use linux_raw_sys::netlink::{nlmsghdr, NLMSG_ALIGNTO, NLMSG_DONE};
 
 use crate::utils::incomplete_array::IncompleteArray;
 
 pub(super) const NLMSGHDR_SIZE: usize = size_of::<nlmsghdr>();
 pub(super) const CN_MSG_SIZE: usize = size_of::<cn_msg>();
 pub(super) const MCAST_OP_SIZE: usize = size_of::<proc_cn_mcast_op>();
+pub(super) const PROC_INPUT_SIZE: usize = size_of::<proc_input>();
 pub(super) const PROC_EVENT_SIZE: usize = size_of::<proc_event>();
 
 pub(super) const NL_MESSAGE_BASE_SIZE: usize = NLMSGHDR_SIZE + CN_MSG_SIZE;
 pub(super) const NL_MESSAGE_MCAST_SIZE: usize = NL_MESSAGE_BASE_SIZE + MCAST_OP_SIZE;
-pub(super) const NL_CONNECTOR_MAX_MSG_SIZE: usize = 16384;
+pub(super) const NL_MESSAGE_INPUT_SIZE: usize = NL_MESSAGE_BASE_SIZE + PROC_INPUT_SIZE;
 
 pub(super) const CN_IDX_PROC: u32 = 0x1;
 pub(super) const CN_VAL_PROC: u32 = 0x1;
@@ -32,6 +33,16 @@ pub(super) enum proc_cn_mcast_op {
     PROC_CN_MCAST_IGNORE = 2,
 }
 
+/// Control message with an event mask, Linux 6.6+ only, older kernels ignore it
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub(super) struct proc_input {
+    pub mcast_op: proc_cn_mcast_op,
+    /// Mask of `proc_cn_event`, the kernel only sends these to the socket
+    pub event_type: u32,
+}
+
 #[allow(non_camel_case_types)]
 #[repr(C)]
 #[derive(Debug)]
@@ -50,7 +61,6 @@ pub(super) struct cn_msg {
 #[allow(non_camel_case_types, unused)]
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
-// TODO: UB no all coverage
 pub(super) enum proc_cn_event {
     /* Use successive bits so the enums can be used to record
      * sets of events as well
@@ -75,6 +85,102 @@ pub(super) enum proc_cn_event {
     PROC_EVENT_EXIT = i32::MIN as isize, // 32bit overflow, make clippy happy with negative literal
 }
 
+impl proc_cn_event {
+    /// `None` for values the kernel may add later, they aren't valid enum values
+    pub(super) fn from_raw(what: u32) -> Option<Self> {
+        const KNOWN: [proc_cn_event; 11] = [
+            proc_cn_event::PROC_EVENT_NONE,
+            proc_cn_event::PROC_EVENT_FORK,
+            proc_cn_event::PROC_EVENT_EXEC,
+            proc_cn_event::PROC_EVENT_UID,
+            proc_cn_event::PROC_EVENT_GID,
+            proc_cn_event::PROC_EVENT_SID,
+            proc_cn_event::PROC_EVENT_PTRACE,
+            proc_cn_event::PROC_EVENT_COMM,
+            proc_cn_event::PROC_EVENT_NONZERO_EXIT,
+            proc_cn_event::PROC_EVENT_COREDUMP,
+            proc_cn_event::PROC_EVENT_EXIT,
+        ];
+
+        KNOWN.into_iter().find(|&x| x as u32 == what)
+    }
+}
+
+/// Reply to a control message, sent with `PROC_EVENT_NONE`
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub(super) struct ack_proc_event {
+    /// Positive errno, 0 on success
+    pub err: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub(super) struct fork_proc_event {
+    pub parent_pid: u32,
+    pub parent_tgid: u32,
+    pub child_pid: u32,
+    pub child_tgid: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub(super) struct exec_proc_event {
+    pub process_pid: u32,
+    pub process_tgid: u32,
+}
+
+/// Shared by `PROC_EVENT_UID` and `PROC_EVENT_GID`, `r`/`e` are uid or gid respectively
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub(super) struct id_proc_event {
+    pub process_pid: u32,
+    pub process_tgid: u32,
+    pub r: u32,
+    pub e: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub(super) struct sid_proc_event {
+    pub process_pid: u32,
+    pub process_tgid: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub(super) struct ptrace_proc_event {
+    pub process_pid: u32,
+    pub process_tgid: u32,
+    pub tracer_pid: u32,
+    pub tracer_tgid: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub(super) struct comm_proc_event {
+    pub process_pid: u32,
+    pub process_tgid: u32,
+    pub comm: [u8; 16],
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub(super) struct coredump_proc_event {
+    pub process_pid: u32,
+    pub process_tgid: u32,
+    pub parent_pid: u32,
+    pub parent_tgid: u32,
+}
+
 #[allow(non_camel_case_types)]
 #[repr(C)]
 #[derive(Debug, Copy, Clone)]
@@ -89,13 +195,75 @@ pub(super) struct exit_proc_event {
 
 #[allow(non_camel_case_types)]
 #[repr(C)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Copy, Clone)]
+pub(super) union proc_event_data {
+    pub ack: ack_proc_event,
+    pub fork: fork_proc_event,
+    pub exec: exec_proc_event,
+    pub id: id_proc_event,
+    pub sid: sid_proc_event,
+    pub ptrace: ptrace_proc_event,
+    pub comm: comm_proc_event,
+    pub coredump: coredump_proc_event,
+    pub exit: exit_proc_event,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Copy, Clone)]
 pub(super) struct proc_event {
-    pub what: proc_cn_event,
+    /// `proc_cn_event`, kept as an integer since the kernel may send unknown values
+    pub what: u32,
     pub cpu: u32,
     /// Number of nano seconds since system boot
     pub timestamp_ns: u64,
-    // specially, [`exit_proc_event`] is the longest struct in proc_event union,
-    // so it's safe to use it as the type of event_data
-    pub event_data: exit_proc_event, /* must be last field of proc_event struct */
+    // every event struct starts with the (pid, tgid) pair it's about,
+    // fork events with the parent's, which lets the cBPF filter match all of them at one offset
+    pub event_data: proc_event_data, /* must be last field of proc_event struct */
+}
+
+/// Exit event of `pid` as the proc connector sends it
+#[cfg(test)]
+pub(super) fn exit_message(seq: u32, pid: u32) -> Vec<u8> {
+    use std::mem::offset_of;
+
+    let len = NL_MESSAGE_BASE_SIZE + PROC_EVENT_SIZE;
+    let mut buf = vec![0; len];
+    let mut write = |offset: usize, bytes: &[u8]| {
+        buf[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+    let cn_msg = |offset| NLMSGHDR_SIZE + offset;
+    let event = |offset| NL_MESSAGE_BASE_SIZE + offset_of!(proc_event, event_data) + offset;
+
+    write(offset_of!(nlmsghdr, nlmsg_len), &(len as u32).to_ne_bytes());
+    write(
+        offset_of!(nlmsghdr, nlmsg_type),
+        &(NLMSG_DONE as u16).to_ne_bytes(),
+    );
+    write(
+        cn_msg(offset_of!(cn_msg, id) + offset_of!(cb_id, idx)),
+        &CN_IDX_PROC.to_ne_bytes(),
+    );
+    write(
+        cn_msg(offset_of!(cn_msg, id) + offset_of!(cb_id, val)),
+        &CN_VAL_PROC.to_ne_bytes(),
+    );
+    write(cn_msg(offset_of!(cn_msg, seq)), &seq.to_ne_bytes());
+    write(
+        cn_msg(offset_of!(cn_msg, len)),
+        &(PROC_EVENT_SIZE as u16).to_ne_bytes(),
+    );
+    write(
+        NL_MESSAGE_BASE_SIZE + offset_of!(proc_event, what),
+        &(proc_cn_event::PROC_EVENT_EXIT as u32).to_ne_bytes(),
+    );
+    write(
+        event(offset_of!(exit_proc_event, process_pid)),
+        &pid.to_ne_bytes(),
+    );
+    write(
+        event(offset_of!(exit_proc_event, process_tgid)),
+        &pid.to_ne_bytes(),
+    );
+    buf
 }
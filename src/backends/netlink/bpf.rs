@@ -11,17 +11,22 @@ use rustix::{
 };
 use BPFFilter as B;
 
-use super::binding::{
-    cb_id, cn_msg, exit_proc_event, nlmsghdr, proc_cn_event, proc_event, CN_IDX_PROC, CN_VAL_PROC,
+use super::{
+    binding::{cb_id, cn_msg, exit_proc_event, nlmsghdr, proc_event, CN_IDX_PROC, CN_VAL_PROC},
+    event::ProcEventKinds,
 };
 
-// cBPF modified from https://github.com/Parrot-Developers/fusion/blob/master/pidwatch/src/pidwatch.c
-// with BSD-3-Clause license
-fn assembly_filter(pids: &[Pid]) -> Vec<BPFFilter> {
-    let mut filter =
-        Vec::with_capacity(15 /* head */ + 1 /* tail */ + 3 /* pid asm */ * pids.len());
+/// Most pids matched by a single filter, which keeps it well below `BPF_MAXINSNS`
+pub(super) const MAX_FILTER_PIDS: usize = 2048;
+/// Pids compared one by one at the leaves of the search tree
+const LEAF_PIDS: usize = 8;
 
-    filter.extend([
+const DROP: u32 = 0x0;
+const ACCEPT: u32 = 0xffffffff;
+
+/* checks every message is a proc connector event from the kernel */
+fn header() -> [BPFFilter; 12] {
+    [
         /* check message's type is NLMSG_DONE */
         B::bpf_stmt(
             BPF_LD | BPF_H | BPF_ABS,
@@ -33,77 +38,261 @@ fn assembly_filter(pids: &[Pid]) -> Vec<BPFFilter> {
             1,
             0,
         ),
-        B::bpf_stmt(BPF_RET | BPF_K, 0x0), /* message is dropped */
+        B::bpf_stmt(BPF_RET | BPF_K, DROP),
         /* check message comes from the kernel */
         B::bpf_stmt(
             BPF_LD | BPF_W | BPF_ABS,
             offset_of!(nlmsghdr, nlmsg_pid) as _,
         ),
         B::bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, 0, 1, 0),
-        B::bpf_stmt(BPF_RET | BPF_K, 0x0), /* message is dropped */
+        B::bpf_stmt(BPF_RET | BPF_K, DROP),
         /* check it's a proc connector event part 1 */
         B::bpf_stmt(
             BPF_LD | BPF_W | BPF_ABS,
             (size_of::<nlmsghdr>() + offset_of!(cn_msg, id) + offset_of!(cb_id, idx)) as _,
         ),
         B::bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, CN_IDX_PROC.to_be(), 1, 0),
-        B::bpf_stmt(BPF_RET | BPF_K, 0x0), /* message is dropped */
+        B::bpf_stmt(BPF_RET | BPF_K, DROP),
         /* check it's a proc connector event part 2 */
         B::bpf_stmt(
             BPF_LD | BPF_W | BPF_ABS,
             (size_of::<nlmsghdr>() + offset_of!(cn_msg, id) + offset_of!(cb_id, val)) as _,
         ),
         B::bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, CN_VAL_PROC.to_be(), 1, 0),
-        B::bpf_stmt(BPF_RET | BPF_K, 0x0), /* message is dropped */
-        /* check it's an exit message*/
+        B::bpf_stmt(BPF_RET | BPF_K, DROP),
+    ]
+}
+
+// cBPF modified from https://github.com/Parrot-Developers/fusion/blob/master/pidwatch/src/pidwatch.c
+// with BSD-3-Clause license
+//
+// `kinds` is a mask of `proc_cn_event`, `pids` limits events to the given TGIDs
+// (parent TGIDs for fork events), all processes pass if it's `None`
+fn assembly_filter(kinds: u32, pids: Option<&[Pid]>) -> Vec<BPFFilter> {
+    let mut filter = Vec::from(header());
+
+    // cn_msg.seq of every kind of event is tracked then, which is advanced by acknowledgements
+    // and kinds unknown to this crate as well, so every message passes
+    if pids.is_none() && kinds == ProcEventKinds::all().bits() {
+        filter.push(B::bpf_stmt(BPF_RET | BPF_K, ACCEPT));
+        return filter;
+    }
+
+    filter.extend([
+        /* check the event kind is subscribed */
         B::bpf_stmt(
             BPF_LD | BPF_W | BPF_ABS,
             (size_of::<nlmsghdr>() + size_of::<cn_msg>() + offset_of!(proc_event, what)) as _,
         ),
-        B::bpf_jump(
-            BPF_JMP | BPF_JEQ | BPF_K,
-            (proc_cn_event::PROC_EVENT_EXIT as u32).to_be(),
-            1,
-            0,
-        ),
-        B::bpf_stmt(BPF_RET | BPF_K, 0x0), /* message is dropped */
+        B::bpf_jump(BPF_JMP | BPF_JSET | BPF_K, kinds.to_be(), 1, 0),
+        B::bpf_stmt(BPF_RET | BPF_K, DROP),
     ]);
 
-    for p in pids {
-        filter.extend([
-            /* check the pid matches */
-            B::bpf_stmt(
-                BPF_LD | BPF_W | BPF_ABS,
-                (size_of::<nlmsghdr>()
-                    + size_of::<cn_msg>()
-                    + offset_of!(proc_event, event_data)
-                    + offset_of!(exit_proc_event, process_tgid)) as _,
-            ),
-            /* here pid has been tested >= 1, so the cast is ok */
-            B::bpf_jump(
+    let Some(pids) = pids else {
+        /* message is sent to user space */
+        filter.push(B::bpf_stmt(BPF_RET | BPF_K, ACCEPT));
+        return filter;
+    };
+
+    // loads convert from network byte order, so compare with byte swapped pids,
+    // which also orders them for the search tree
+    let mut keys = pids
+        .iter()
+        .map(|p| (p.as_raw_nonzero().get() as u32).to_be())
+        .collect::<Vec<_>>();
+    keys.sort_unstable();
+    keys.dedup();
+
+    /* load the tgid once, it's kept in A by the search tree */
+    filter.push(B::bpf_stmt(
+        BPF_LD | BPF_W | BPF_ABS,
+        (size_of::<nlmsghdr>()
+            + size_of::<cn_msg>()
+            + offset_of!(proc_event, event_data)
+            + offset_of!(exit_proc_event, process_tgid)) as _,
+    ));
+    filter.extend(search_tree(&keys));
+
+    filter
+}
+
+/// Accept the message if A is one of the sorted `keys`
+fn search_tree(keys: &[u32]) -> Vec<BPFFilter> {
+    if keys.len() <= LEAF_PIDS {
+        let mut leaf = Vec::with_capacity(keys.len() + 2);
+
+        for (i, &key) in keys.iter().enumerate() {
+            /* on match skip the rest and the drop below */
+            leaf.push(B::bpf_jump(
                 BPF_JMP | BPF_JEQ | BPF_K,
-                p.as_raw_nonzero().get().to_be() as _,
+                key,
+                (keys.len() - i) as u8,
                 0,
-                1,
-            ),
-            /* message is sent to user space */
-            B::bpf_stmt(BPF_RET | BPF_K, 0xffffffff),
+            ));
+        }
+        leaf.extend([
+            B::bpf_stmt(BPF_RET | BPF_K, DROP),
+            B::bpf_stmt(BPF_RET | BPF_K, ACCEPT),
         ]);
+
+        return leaf;
     }
 
+    let (left, right) = keys.split_at(keys.len() / 2);
+    let pivot = right[0];
+    let (left, right) = (search_tree(left), search_tree(right));
+
+    let mut node = Vec::with_capacity(2 + left.len() + right.len());
+    match u8::try_from(left.len()) {
+        /* greater or equal go right, skipping the left subtree */
+        Ok(offset) => node.push(B::bpf_jump(BPF_JMP | BPF_JGE | BPF_K, pivot, offset, 0)),
+        /* conditional jumps are 8 bits only, the long one is unconditional */
+        Err(_) => node.extend([
+            B::bpf_jump(BPF_JMP | BPF_JGE | BPF_K, pivot, 0, 1),
+            B::bpf_stmt(BPF_JMP | BPF_JA, left.len() as u32),
+        ]),
+    }
+    node.extend(left);
+    node.extend(right);
+
+    node
+}
+
+/// Only pass the reply to a control message sent with `ack`
+pub fn apply_ack_filter(fd: BorrowedFd, ack: u32) -> Result<()> {
+    let mut filter = Vec::from(header());
+
     filter.extend([
-        B::bpf_stmt(BPF_RET | BPF_K, 0x0), /* message is dropped */
+        /* check it's an acknowledgement */
+        B::bpf_stmt(
+            BPF_LD | BPF_W | BPF_ABS,
+            (size_of::<nlmsghdr>() + size_of::<cn_msg>() + offset_of!(proc_event, what)) as _,
+        ),
+        B::bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, 0, 1, 0),
+        B::bpf_stmt(BPF_RET | BPF_K, DROP),
+        /* check it replies to our message */
+        B::bpf_stmt(
+            BPF_LD | BPF_W | BPF_ABS,
+            (size_of::<nlmsghdr>() + offset_of!(cn_msg, ack)) as _,
+        ),
+        B::bpf_jump(BPF_JMP | BPF_JEQ | BPF_K, ack.wrapping_add(1).to_be(), 1, 0),
+        B::bpf_stmt(BPF_RET | BPF_K, DROP),
+        B::bpf_stmt(BPF_RET | BPF_K, ACCEPT),
     ]);
 
-    filter
+    BPFFProg::new(&filter)
+        .attach_filter(fd.as_raw_fd())
+        .map_err(Error::from_raw_os_error)
 }
 
-pub fn apply_bpf_filter(fd: BorrowedFd, pid: &[Pid]) -> Result<()> {
-    BPFFProg::new(&assembly_filter(pid))
+pub fn apply_bpf_filter(fd: BorrowedFd, kinds: u32, pids: Option<&[Pid]>) -> Result<()> {
+    BPFFProg::new(&assembly_filter(kinds, pids))
         .attach_filter(fd.as_raw_fd())
         .map_err(Error::from_raw_os_error)
 }
 
-pub fn detach_bpf_filter(fd: BorrowedFd) -> Result<()> {
-    detach_filter(fd.as_raw_fd()).map_err(Error::from_raw_os_error)
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, mem::align_of};
+
+    use super::*;
+    use crate::backends::netlink::binding::{exit_message, NL_MESSAGE_BASE_SIZE};
+
+    // BPFFilter hides its fields, it's read as the sock_filter it's attached as
+    const _: () = assert!(
+        size_of::<BPFFilter>() == size_of::<libc::sock_filter>()
+            && align_of::<BPFFilter>() == align_of::<libc::sock_filter>()
+    );
+
+    /// Runs the subset of cBPF the filters are made of, returns the accepted length
+    fn run(filter: &[BPFFilter], message: &[u8]) -> u32 {
+        let filter = filter
+            .iter()
+            .map(|x| unsafe { std::mem::transmute_copy::<BPFFilter, libc::sock_filter>(x) })
+            .collect::<Vec<_>>();
+        let load = |k: u32, n: usize| {
+            let bytes = message.get(k as usize..)?.get(..n)?;
+            Some(bytes.iter().fold(0, |a, &b| a << 8 | b as u32))
+        };
+
+        let (mut pc, mut a) = (0, 0);
+        loop {
+            let insn = filter[pc];
+            pc += 1;
+
+            let jump = |cond: bool| match cond {
+                true => insn.jt as usize,
+                false => insn.jf as usize,
+            };
+            match insn.code {
+                // out of bounds loads drop the message, like the kernel does
+                c if c == BPF_LD | BPF_W | BPF_ABS => match load(insn.k, 4) {
+                    Some(x) => a = x,
+                    None => return DROP,
+                },
+                c if c == BPF_LD | BPF_H | BPF_ABS => match load(insn.k, 2) {
+                    Some(x) => a = x,
+                    None => return DROP,
+                },
+                c if c == BPF_JMP | BPF_JEQ | BPF_K => pc += jump(a == insn.k),
+                c if c == BPF_JMP | BPF_JGE | BPF_K => pc += jump(a >= insn.k),
+                c if c == BPF_JMP | BPF_JSET | BPF_K => pc += jump(a & insn.k != 0),
+                c if c == BPF_JMP | BPF_JA => pc += insn.k as usize,
+                c if c == BPF_RET | BPF_K => return insn.k,
+                c => panic!("unexpected instruction {c:#x}"),
+            }
+        }
+    }
+
+    fn accepts(filter: &[BPFFilter], tgid: u32) -> bool {
+        match run(filter, &exit_message(0, tgid)) {
+            ACCEPT => true,
+            DROP => false,
+            x => panic!("unexpected return value {x:#x}"),
+        }
+    }
+
+    #[test]
+    fn pid_filter() {
+        let kinds = ProcEventKinds::EXIT.bits();
+
+        for size in [0, 1, 8, 9, 2048] {
+            // spread over every byte of the pid, so the byte swapped order differs
+            let pids = (1..=size as u64)
+                .map(|i| (i * 2_654_435_761 % 4_000_000) as u32 + 2)
+                .collect::<HashSet<_>>();
+            assert_eq!(pids.len(), size);
+            let list = pids
+                .iter()
+                .map(|&x| Pid::from_raw(x as i32).unwrap())
+                .collect::<Vec<_>>();
+            let filter = assembly_filter(kinds, Some(&list));
+
+            for &pid in &pids {
+                assert!(accepts(&filter, pid), "{pid} of {size} pids is dropped");
+
+                for other in [pid - 1, pid + 1, pid ^ 0x100, pid ^ 0x10000] {
+                    if !pids.contains(&other) {
+                        assert!(!accepts(&filter, other), "{other} of {size} pids passes");
+                    }
+                }
+            }
+            for other in [1, u32::MAX] {
+                assert!(!accepts(&filter, other), "{other} of {size} pids passes");
+            }
+        }
+    }
+
+    #[test]
+    fn kind_filter() {
+        let message = exit_message(0, 2);
+        let pids = [Pid::from_raw(2).unwrap()];
+
+        let filter = assembly_filter(ProcEventKinds::FORK.bits(), Some(&pids));
+        assert_eq!(run(&filter, &message), DROP);
+        let filter = assembly_filter(ProcEventKinds::EXIT.bits(), None);
+        assert_eq!(run(&filter, &message), ACCEPT);
+        let filter = assembly_filter(ProcEventKinds::EXIT.bits(), Some(&pids));
+        assert_eq!(run(&filter, &message[..NL_MESSAGE_BASE_SIZE]), DROP);
+    }
 }
@@ -1,41 +1,318 @@
 use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    ffi::CStr,
+    fmt,
     io::{Error, ErrorKind, Result},
     mem::size_of,
-    ptr::addr_of,
-    time::Duration,
+    ptr::{self, addr_of, addr_of_mut},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "async-netlink")]
+use std::{
+    future::poll_fn,
+    ops::Deref,
+    task::{ready, Context, Poll},
 };
 
 use libc::sockaddr;
 use linux_raw_sys::netlink;
 use rustix::{
-    event,
-    fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd},
-    net::{self, netlink as rustix_netlink, AddressFamily, RecvFlags, SendFlags, SocketType},
-    process::{self, Pid},
+    event::{self, epoll},
+    fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd},
+    io::Errno,
+    net::{
+        self, netlink as rustix_netlink, sockopt, AddressFamily, RecvFlags, SendFlags, SocketFlags,
+        SocketType,
+    },
+    process::Pid,
 };
 
-use super::{binding::*, bpf};
+#[cfg(feature = "async-netlink")]
+use tokio::io::{unix::AsyncFd, Interest};
+
+#[cfg(feature = "ebpf")]
+use super::ebpf;
+use super::{
+    binding::*,
+    bpf,
+    event::{ProcEvent, ProcEventKinds},
+    message::{self, MessageError},
+};
 use crate::utils::incomplete_array::IncompleteArray;
 
+/// Longest wait for the kernel to acknowledge LISTEN, it's sent before `send` returns
+const ACK_TIMEOUT: Duration = Duration::from_millis(500);
+/// Most datagrams received by a single `recvmmsg`
+const RECV_BATCH: usize = 32;
+/// A proc connector datagram carries a single event, far below this
+const RECV_SLOT_SIZE: usize = 1024;
+
+/// Proc connector subscription, made of as many sockets as its filters need.
+///
+/// Readiness of all sockets is reported through a single epoll fd.
 #[derive(Debug)]
 pub(super) struct NetlinkConnection {
+    epoll: OwnedFd,
+    sockets: Mutex<Sockets>,
+    kinds: ProcEventKinds,
+    listening: AtomicBool,
+    recv_buffer_size: AtomicUsize,
+    overflows: AtomicU64,
+    slots: Mutex<RecvSlots>,
+    inbox: Mutex<Inbox>,
+}
+
+#[derive(Debug)]
+struct Sockets {
+    /// The first one is never dropped
+    list: Vec<Socket>,
+    /// Every event passes the first socket, there are no others then
+    unfiltered: bool,
+    /// Socket read first next time, so that none of them starves
+    cursor: usize,
+    /// Filter of the first socket if eBPF is permitted, there are no others then
+    #[cfg(feature = "ebpf")]
+    map: Option<ebpf::PidMapFilter>,
+}
+
+#[derive(Debug)]
+struct Socket {
     fd: OwnedFd,
+    /// Assigned by the kernel, unique among netlink sockets
+    port: u32,
+    /// Pids passed by the filter unless the connection is unfiltered, they never move to
+    /// another socket, so no event is dropped while the filters are updated one by one
+    pids: HashSet<Pid>,
+    /// The kernel counted the socket as a listener, it's balanced by a single IGNORE
+    listening: bool,
+}
+
+impl Socket {
+    fn ignore(&mut self) -> Result<()> {
+        if self.listening {
+            self.send_control_message(proc_cn_mcast_op::PROC_CN_MCAST_IGNORE, 0, None)?;
+            self.listening = false;
+        }
+
+        Ok(())
+    }
+
+    /// `kinds` are sent with `proc_input`, the kernel doesn't send the others to the socket then,
+    /// the acknowledgement of the message carries `ack + 1`
+    fn send_control_message(
+        &self,
+        control_op: proc_cn_mcast_op,
+        ack: u32,
+        kinds: Option<ProcEventKinds>,
+    ) -> Result<()> {
+        let event_type = kinds.map(|x| x.bits());
+        let (buf, len) = make_netlink_control_message(self.port, control_op, ack, event_type);
+
+        net::send(&self.fd, &buf[..len], SendFlags::empty())?;
+
+        Ok(())
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        let _ = self.ignore();
+    }
+}
+
+#[cfg(feature = "ebpf")]
+impl Sockets {
+    fn update_map(&mut self, wanted: &HashSet<Pid>) -> Result<()> {
+        let map = self.map.as_ref().unwrap();
+        let socket = &mut self.list[0];
+
+        for &pid in socket.pids.difference(wanted) {
+            map.remove(pid)?;
+        }
+        for &pid in wanted.difference(&socket.pids) {
+            map.insert(pid)?;
+        }
+        socket.pids.clone_from(wanted);
+
+        self.attach_map()
+    }
+
+    /// Only the changed pids are looked up in the map
+    fn update_map_pids(&mut self, changes: &HashMap<Pid, bool>) -> Result<()> {
+        let map = self.map.as_ref().unwrap();
+        let socket = &mut self.list[0];
+
+        for (&pid, &watched) in changes {
+            match watched {
+                true if !socket.pids.contains(&pid) => map.insert(pid)?,
+                false if socket.pids.contains(&pid) => map.remove(pid)?,
+                _ => continue,
+            }
+            // only once the map holds it, so that a fallback sees what the map holds
+            match watched {
+                true => socket.pids.insert(pid),
+                false => socket.pids.remove(&pid),
+            };
+        }
+
+        self.attach_map()
+    }
+
+    fn attach_map(&mut self) -> Result<()> {
+        if self.unfiltered {
+            let map = self.map.as_ref().unwrap();
+            map.attach(self.list[0].fd.as_fd())?;
+            self.unfiltered = false;
+        }
+
+        Ok(())
+    }
+}
+
+/// Update of the pids passed by the filter
+#[derive(Debug)]
+pub(super) enum PidUpdate {
+    /// Pass these only
+    All(Vec<Pid>),
+    /// Also pass the pids mapped to `true`, and stop passing those mapped to `false`
+    Changes(HashMap<Pid, bool>),
+}
+
+/// Buffers of a batch receive, one per datagram
+struct RecvSlots(Box<[[u8; RECV_SLOT_SIZE]; RECV_BATCH]>);
+
+impl fmt::Debug for RecvSlots {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvSlots").finish_non_exhaustive()
+    }
+}
+
+/// Events received but not read yet.
+///
+/// `cn_msg.seq` of an unfiltered connection is tracked to find lost events, the kernel counts
+/// messages per CPU before the socket filter runs, acknowledgements sent to other listeners
+/// included, so the sequence only has no gaps if every message is passed.
+#[derive(Debug, Default)]
+struct Inbox {
+    /// Last seq received from each CPU, `None` while events are filtered
+    last: Option<HashMap<u32, u32>>,
+    /// In order of arrival, `None` stands for lost events
+    queue: VecDeque<Option<ProcEvent>>,
+}
+
+impl Inbox {
+    /// Queue an event, returns whether events were lost before it
+    fn push(&mut self, seq: u32, event: ProcEvent) -> bool {
+        let lost = self.advance(event.cpu(), seq);
+        self.queue.push_back(Some(event));
+
+        lost
+    }
+
+    /// Track a message which isn't queued, returns whether events were lost before it
+    fn advance(&mut self, cpu: u32, seq: u32) -> bool {
+        let lost = match &mut self.last {
+            Some(last) => matches!(
+                last.insert(cpu, seq),
+                Some(prev) if prev.wrapping_add(1) != seq
+            ),
+            None => false,
+        };
+
+        if lost {
+            self.queue.push_back(None);
+        }
+
+        lost
+    }
+
+    fn push_lost(&mut self) {
+        // the gap following is the same loss
+        if let Some(last) = &mut self.last {
+            last.clear();
+        }
+        self.queue.push_back(None);
+    }
+
+    /// The next event, lost events are reported as `ENOBUFS`
+    fn pop(&mut self) -> Option<Result<ProcEvent>> {
+        let event = self.queue.pop_front()?;
+        Some(event.ok_or_else(|| Errno::NOBUFS.into()))
+    }
+
+    /// Events up to the next loss, which is reported as `ENOBUFS` on its own
+    fn drain(&mut self) -> Option<Result<Vec<ProcEvent>>> {
+        if self.queue.front()?.is_none() {
+            self.queue.pop_front();
+            return Some(Err(Errno::NOBUFS.into()));
+        }
+
+        let n = self
+            .queue
+            .iter()
+            .position(Option::is_none)
+            .unwrap_or(self.queue.len());
+
+        Some(Ok(self.queue.drain(..n).flatten().collect()))
+    }
 }
 
 impl NetlinkConnection {
-    pub(super) fn new() -> Result<Self> {
-        let fd = net::socket(
+    pub(super) fn new(kinds: ProcEventKinds) -> Result<Self> {
+        let mut conn = Self {
+            epoll: epoll::create(epoll::CreateFlags::CLOEXEC)?,
+            sockets: Mutex::new(Sockets {
+                list: Vec::new(),
+                unfiltered: false,
+                cursor: 0,
+                #[cfg(feature = "ebpf")]
+                map: None,
+            }),
+            kinds,
+            listening: AtomicBool::new(false),
+            recv_buffer_size: AtomicUsize::new(0),
+            overflows: AtomicU64::new(0),
+            slots: Mutex::new(RecvSlots(Box::new([[0; RECV_SLOT_SIZE]; RECV_BATCH]))),
+            inbox: Default::default(),
+        };
+
+        let socket = conn.open_socket()?;
+
+        // cBPF filters are used if the kernel refuses it
+        #[cfg(feature = "ebpf")]
+        {
+            conn.sockets.get_mut().unwrap().map = ebpf::PidMapFilter::new(kinds.bits())
+                .and_then(|map| map.attach(socket.fd.as_fd()).map(|_| map))
+                .ok();
+        }
+
+        conn.sockets.get_mut().unwrap().list.push(socket);
+
+        Ok(conn)
+    }
+
+    /// Bound socket that drops everything until its filter is updated
+    fn open_socket(&self) -> Result<Socket> {
+        // readiness is reported by epoll, a spurious wakeup must not block in recv
+        let fd = net::socket_with(
             AddressFamily::NETLINK,
             SocketType::DGRAM,
+            SocketFlags::NONBLOCK | SocketFlags::CLOEXEC,
             Some(rustix_netlink::CONNECTOR),
         )?;
 
-        let self_pid = process::getpid().as_raw_nonzero().get();
+        // before binding, so that nothing is queued
+        bpf::apply_bpf_filter(fd.as_fd(), self.kinds.bits(), Some(&[]))?;
 
         let sa_nl = netlink::sockaddr_nl {
             nl_family: AddressFamily::NETLINK.as_raw(),
             nl_pad: 0, // unspecified
-            nl_pid: self_pid as u32,
+            nl_pid: 0, // assigned by the kernel, any number of sockets can coexist
             nl_groups: CN_IDX_PROC,
         };
 
@@ -52,114 +329,698 @@ impl NetlinkConnection {
             return Err(Error::last_os_error());
         }
 
-        Ok(Self { fd })
+        match self.recv_buffer_size.load(Ordering::Relaxed) {
+            0 => (),
+            size => set_recv_buffer_size(&fd, size)?,
+        }
+
+        epoll::add(
+            &self.epoll,
+            &fd,
+            epoll::EventData::new_u64(fd.as_raw_fd() as u64),
+            epoll::EventFlags::IN,
+        )?;
+
+        let mut socket = Socket {
+            port: local_port(&fd)?,
+            fd,
+            pids: HashSet::new(),
+            listening: false,
+        };
+
+        if self.listening.load(Ordering::Relaxed) {
+            self.listen(&mut socket)?;
+            bpf::apply_bpf_filter(socket.fd.as_fd(), self.kinds.bits(), Some(&[]))?;
+        }
+
+        Ok(socket)
     }
 
+    /// Fails with [`ErrorKind::PermissionDenied`] if the kernel refuses to send events, does
+    /// nothing once started
     pub(super) fn start(&self) -> Result<()> {
-        let buf = make_netlink_control_message(proc_cn_mcast_op::PROC_CN_MCAST_LISTEN);
+        let mut sockets = self.sockets.lock().unwrap_or_else(|x| x.into_inner());
 
-        net::send(&self.fd, &buf, SendFlags::empty())?;
+        if self.listening.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        for socket in &mut sockets.list {
+            self.listen(socket)?;
+        }
+        self.listening.store(true, Ordering::Relaxed);
+
+        // events before start are not reported anyway
+        for i in 0..sockets.list.len() {
+            self.apply_filter(&sockets, i)?;
+        }
 
         Ok(())
     }
 
+    /// IGNORE is only sent by sockets which are listening
     pub(super) fn stop(&self) -> Result<()> {
-        let buf = make_netlink_control_message(proc_cn_mcast_op::PROC_CN_MCAST_IGNORE);
+        let mut sockets = self.sockets.lock().unwrap_or_else(|x| x.into_inner());
+
+        self.listening.store(false, Ordering::Relaxed);
+        for socket in &mut sockets.list {
+            socket.ignore()?;
+        }
+
+        Ok(())
+    }
+
+    /// LISTEN and wait for the acknowledgement, the filter of the socket is replaced meanwhile
+    fn listen(&self, socket: &mut Socket) -> Result<()> {
+        if socket.listening {
+            return Ok(());
+        }
+
+        // the port is unique among netlink sockets, so is the acknowledgement
+        let ack = socket.port;
+        bpf::apply_ack_filter(socket.fd.as_fd(), ack)?;
 
-        net::send(&self.fd, &buf, SendFlags::empty())?;
+        // every kind is subscribed by the legacy message,
+        // the kernel drops the acknowledgement otherwise
+        let listen = proc_cn_mcast_op::PROC_CN_MCAST_LISTEN;
+        socket.send_control_message(listen, ack, None)?;
+        wait_ack(&socket.fd, ack)?;
+        socket.listening = true;
+
+        // Linux 6.6+ counts listeners per socket, LISTEN again only updates the event mask
+        if proc_input_supported() && !self.kinds.is_all() {
+            socket.send_control_message(listen, 0, Some(self.kinds))?;
+        }
 
         Ok(())
     }
 
+    /// Attach the filter of the `i`th socket again
+    fn apply_filter(&self, sockets: &Sockets, i: usize) -> Result<()> {
+        let socket = &sockets.list[i];
+        let unfiltered = i == 0 && sockets.unfiltered;
+
+        #[cfg(feature = "ebpf")]
+        if let (0, Some(map), false) = (i, &sockets.map, unfiltered) {
+            return map.attach(socket.fd.as_fd());
+        }
+
+        let pids = socket.pids.iter().copied().collect::<Vec<_>>();
+        let pids = (!unfiltered).then_some(&pids[..]);
+        bpf::apply_bpf_filter(socket.fd.as_fd(), self.kinds.bits(), pids)
+    }
+
+    /// Only pass events about `pids`, or about every process if `None`
+    ///
+    /// Pids are spread over several sockets when a single cBPF filter can't hold them,
+    /// the eBPF map of the first socket holds all of them.
     pub(super) fn interest(&self, pids: Option<&[Pid]>) -> Result<()> {
+        let mut sockets = self.sockets.lock().unwrap_or_else(|x| x.into_inner());
+
         match pids {
-            Some(pids) => bpf::apply_bpf_filter(self.fd.as_fd(), pids),
-            None => bpf::detach_bpf_filter(self.fd.as_fd()),
+            #[cfg(feature = "ebpf")]
+            Some(pids) if sockets.map.is_some() => self.update_map(&mut sockets, pids)?,
+            Some(pids) => self.update_sockets(&mut sockets, pids)?,
+            None => {
+                // pids of the first socket are passed again once it's filtered
+                self.close_sockets(sockets.list.drain(1..));
+                bpf::apply_bpf_filter(sockets.list[0].fd.as_fd(), self.kinds.bits(), None)?;
+                sockets.unfiltered = true;
+            }
         }
+
+        let unfiltered = pids.is_none() && self.kinds.is_all();
+        let mut inbox = self.inbox.lock().unwrap_or_else(|x| x.into_inner());
+        inbox.last = unfiltered.then(HashMap::new);
+
+        Ok(())
+    }
+
+    /// Apply `update` to the filters, changes only touch the sockets holding the changed pids,
+    /// or the map of the first one
+    pub(super) fn update(&self, update: &PidUpdate) -> Result<()> {
+        let changes = match update {
+            PidUpdate::All(pids) => return self.interest(Some(pids)),
+            PidUpdate::Changes(changes) => changes,
+        };
+
+        let mut sockets = self.sockets.lock().unwrap_or_else(|x| x.into_inner());
+
+        match changes {
+            #[cfg(feature = "ebpf")]
+            changes if sockets.map.is_some() => self.update_map_pids(&mut sockets, changes)?,
+            changes => self.update_socket_pids(&mut sockets, changes)?,
+        }
+
+        let mut inbox = self.inbox.lock().unwrap_or_else(|x| x.into_inner());
+        inbox.last = None;
+
+        Ok(())
+    }
+
+    fn update_sockets(&self, sockets: &mut Sockets, pids: &[Pid]) -> Result<()> {
+        let wanted = pids.iter().copied().collect::<HashSet<_>>();
+
+        let mut changed = vec![false; sockets.list.len()];
+        changed[0] = sockets.unfiltered;
+        sockets.unfiltered = false;
+
+        for (socket, changed) in sockets.list.iter_mut().zip(&mut changed) {
+            let len = socket.pids.len();
+            socket.pids.retain(|x| wanted.contains(x));
+            *changed |= socket.pids.len() != len;
+        }
+
+        for pid in wanted {
+            if !sockets.list.iter().any(|x| x.pids.contains(&pid)) {
+                self.place(sockets, pid, &mut changed)?;
+            }
+        }
+
+        self.refilter(sockets, changed)
+    }
+
+    fn update_socket_pids(
+        &self,
+        sockets: &mut Sockets,
+        changes: &HashMap<Pid, bool>,
+    ) -> Result<()> {
+        let mut changed = vec![false; sockets.list.len()];
+        changed[0] = sockets.unfiltered;
+        sockets.unfiltered = false;
+
+        // removed first, their room is reused
+        for (pid, _) in changes.iter().filter(|x| !x.1) {
+            if let Some(i) = sockets.list.iter().position(|x| x.pids.contains(pid)) {
+                sockets.list[i].pids.remove(pid);
+                changed[i] = true;
+            }
+        }
+
+        for (&pid, _) in changes.iter().filter(|x| *x.1) {
+            if !sockets.list.iter().any(|x| x.pids.contains(&pid)) {
+                self.place(sockets, pid, &mut changed)?;
+            }
+        }
+
+        self.refilter(sockets, changed)
+    }
+
+    /// Add `pid` to the first socket with room, or to a new one
+    fn place(&self, sockets: &mut Sockets, pid: Pid, changed: &mut Vec<bool>) -> Result<()> {
+        let i = match sockets
+            .list
+            .iter()
+            .position(|x| x.pids.len() < bpf::MAX_FILTER_PIDS)
+        {
+            Some(i) => i,
+            None => {
+                sockets.list.push(self.open_socket()?);
+                changed.push(true);
+                sockets.list.len() - 1
+            }
+        };
+
+        sockets.list[i].pids.insert(pid);
+        changed[i] = true;
+
+        Ok(())
+    }
+
+    /// Attach the filters of the `changed` sockets again, and close the empty ones
+    fn refilter(&self, sockets: &mut Sockets, changed: Vec<bool>) -> Result<()> {
+        // new sockets first, pids moved from the first one by the eBPF fallback are never dropped
+        for (socket, changed) in sockets.list.iter().zip(changed).rev() {
+            if changed {
+                let pids = socket.pids.iter().copied().collect::<Vec<_>>();
+                bpf::apply_bpf_filter(socket.fd.as_fd(), self.kinds.bits(), Some(&pids))?;
+            }
+        }
+
+        // empty sockets pass nothing, dropping them loses nothing
+        let empty = (1..sockets.list.len())
+            .rev()
+            .filter(|&i| sockets.list[i].pids.is_empty())
+            .collect::<Vec<_>>();
+        self.close_sockets(empty.into_iter().map(|i| sockets.list.remove(i)));
+
+        Ok(())
+    }
+
+    /// Only the map of the first socket is updated, pids are looked up in constant time
+    #[cfg(feature = "ebpf")]
+    fn update_map(&self, sockets: &mut Sockets, pids: &[Pid]) -> Result<()> {
+        let wanted = pids.iter().copied().collect::<HashSet<_>>();
+
+        match sockets.update_map(&wanted) {
+            Ok(()) => Ok(()),
+            // most likely the map is full
+            Err(_) => {
+                sockets.map = None;
+                sockets.list[0].pids.clear();
+                sockets.unfiltered = true;
+                self.update_sockets(sockets, pids)
+            }
+        }
+    }
+
+    #[cfg(feature = "ebpf")]
+    fn update_map_pids(&self, sockets: &mut Sockets, changes: &HashMap<Pid, bool>) -> Result<()> {
+        match sockets.update_map_pids(changes) {
+            Ok(()) => Ok(()),
+            // as above, the pids the map was meant to hold move to cBPF filters
+            Err(_) => {
+                let mut pids = std::mem::take(&mut sockets.list[0].pids);
+                for (&pid, &watched) in changes {
+                    match watched {
+                        true => pids.insert(pid),
+                        false => pids.remove(&pid),
+                    };
+                }
+
+                sockets.map = None;
+                sockets.unfiltered = true;
+                self.update_sockets(sockets, &pids.into_iter().collect::<Vec<_>>())
+            }
+        }
+    }
+
+    fn close_sockets(&self, sockets: impl Iterator<Item = Socket>) {
+        // dropping sends IGNORE and removes it from epoll
+        sockets.for_each(drop);
+    }
+
+    /// Enlarge the receive buffer of every socket, beyond `net.core.rmem_max` if permitted
+    pub(super) fn set_recv_buffer_size(&self, size: usize) -> Result<()> {
+        let sockets = self.sockets.lock().unwrap_or_else(|x| x.into_inner());
+
+        for socket in &sockets.list {
+            set_recv_buffer_size(&socket.fd, size)?;
+        }
+        self.recv_buffer_size.store(size, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Number of times events were lost since the connection was created
+    pub(super) fn overflows(&self) -> u64 {
+        self.overflows.load(Ordering::Relaxed)
     }
 
     // WARNING: multiple reader in the same time may cause unwanted behavior.
     // current polling implementation is atomic, althrough it's thread-safe in Rust semantics,
     pub(super) fn read_event(
         &self,
-        buf: &mut [u8; NL_CONNECTOR_MAX_MSG_SIZE],
         timeout: Option<Duration>,
-        aborter_fd: BorrowedFd,
-    ) -> Result<Pid> {
-        let timeout = match timeout {
-            Some(timeout) => timeout.as_millis().try_into().unwrap_or(i32::MAX),
-            None => -1,
-        };
+        aborter_fd: Option<BorrowedFd>,
+    ) -> Result<ProcEvent> {
+        self.read_with(timeout, aborter_fd, Inbox::pop)
+    }
+
+    /// Like [`Self::read_event`], but every event received so far is returned at once,
+    /// lost events are reported as `ENOBUFS` between batches
+    pub(super) fn read_events(
+        &self,
+        timeout: Option<Duration>,
+        aborter_fd: Option<BorrowedFd>,
+    ) -> Result<Vec<ProcEvent>> {
+        self.read_with(timeout, aborter_fd, Inbox::drain)
+    }
+
+    fn read_with<T>(
+        &self,
+        timeout: Option<Duration>,
+        aborter_fd: Option<BorrowedFd>,
+        take: fn(&mut Inbox) -> Option<Result<T>>,
+    ) -> Result<T> {
+        if let Some(r) = take(&mut self.inbox.lock().unwrap_or_else(|x| x.into_inner())) {
+            return r;
+        }
+
+        let deadline = timeout.map(|x| Instant::now() + x);
 
-        let n = {
-            let nl_fd = self.fd.as_fd();
+        loop {
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    timeout.as_millis().try_into().unwrap_or(i32::MAX)
+                }
+                None => -1,
+            };
+            let nl_fd = self.epoll.as_fd();
 
             let mut fds = [
                 event::PollFd::new(&nl_fd, event::PollFlags::IN),
-                event::PollFd::new(&aborter_fd, event::PollFlags::IN),
+                event::PollFd::new(aborter_fd.as_ref().unwrap_or(&nl_fd), event::PollFlags::IN),
             ];
+            let fds = match aborter_fd {
+                Some(_) => &mut fds[..],
+                None => &mut fds[..1],
+            };
 
-            let poll_result = event::poll(&mut fds, timeout)?;
+            let poll_result = event::poll(fds, timeout)?;
 
             if poll_result == 0 {
                 return Err(ErrorKind::TimedOut.into());
-            } else if fds[1].revents().contains(event::PollFlags::IN) {
+            } else if aborter_fd.is_some() && fds[1].revents().contains(event::PollFlags::IN) {
                 return Err(ErrorKind::ConnectionAborted.into());
             }
 
-            // then netlink fd must be readable
-            net::recv(nl_fd, buf, RecvFlags::empty())?
-        };
-
-        if n == 0 {
-            return Err(ErrorKind::UnexpectedEof.into());
+            // then one of the sockets must be readable, unless another reader was faster
+            match self.try_take(take) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+                r => return r,
+            }
         }
+    }
 
-        // TODO: erase dirty data in buf
-        parse_netlink_event_message(buf).ok_or(ErrorKind::InvalidData.into())
+    /// Read an event without blocking, fails with [`ErrorKind::WouldBlock`] if there is none
+    #[cfg(feature = "async-netlink")]
+    pub(super) fn try_read_event(&self) -> Result<ProcEvent> {
+        self.try_take(Inbox::pop)
     }
 
-    // WARNING: multiple reader in the same time may cause unwanted behavior.
-    // current polling implementation is atomic, althrough it's thread-safe in Rust semantics,
+    /// Read every event received so far without blocking, see [`Self::read_events`]
     #[cfg(feature = "async-netlink")]
-    pub(super) async fn read_event_async(
-        &self,
-        buf: &mut [u8; NL_CONNECTOR_MAX_MSG_SIZE],
-    ) -> Result<Pid> {
-        let fd = tokio::io::unix::AsyncFd::new(self.fd.as_fd())?;
+    pub(super) fn try_read_events(&self) -> Result<Vec<ProcEvent>> {
+        self.try_take(Inbox::drain)
+    }
 
+    /// Take queued events, or receive more until there are some, fails with
+    /// [`ErrorKind::WouldBlock`] only once every socket is drained
+    fn try_take<T>(&self, take: fn(&mut Inbox) -> Option<Result<T>>) -> Result<T> {
         loop {
-            let mut guard = fd.readable().await?;
-
-            match guard.try_io(|inner| {
-                net::recv(inner.get_ref(), buf, RecvFlags::empty()).map_err(Into::into)
-            }) {
-                Ok(Ok(n)) => {
-                    if n == 0 {
-                        return Err(ErrorKind::UnexpectedEof.into());
+            if let Some(r) = take(&mut self.inbox.lock().unwrap_or_else(|x| x.into_inner())) {
+                return r;
+            }
+
+            // a batch may hold no event at all, like acknowledgements for other listeners,
+            // readiness is edge triggered so the sockets must be drained
+            self.receive()?;
+        }
+    }
+
+    /// Receive a batch of datagrams from the sockets in turn and queue every event in them,
+    /// fails with [`ErrorKind::WouldBlock`] if none of them has any
+    fn receive(&self) -> Result<()> {
+        let mut slots = self.slots.lock().unwrap_or_else(|x| x.into_inner());
+        let mut sockets = self.sockets.lock().unwrap_or_else(|x| x.into_inner());
+        let len = sockets.list.len();
+
+        // slots used, a socket may have reported an overflow only
+        let mut received = 0;
+        let mut ready = false;
+        let start = sockets.cursor;
+
+        for i in (0..len).map(|x| (start + x) % len) {
+            if received == RECV_BATCH {
+                break;
+            }
+
+            let free = &mut slots.0[received..];
+            let datagrams = match recv_batch(&sockets.list[i].fd, free) {
+                Ok(datagrams) => datagrams,
+                Err(Errno::AGAIN) => continue,
+                // the socket overflowed, the error is reported once and the socket keeps working
+                Err(Errno::NOBUFS) => {
+                    self.overflows.fetch_add(1, Ordering::Relaxed);
+                    self.inbox
+                        .lock()
+                        .unwrap_or_else(|x| x.into_inner())
+                        .push_lost();
+
+                    sockets.cursor = (i + 1) % len;
+                    ready = true;
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            let mut inbox = self.inbox.lock().unwrap_or_else(|x| x.into_inner());
+            for (slot, (n, truncated)) in free.iter().zip(datagrams) {
+                let mut lost = truncated;
+
+                for message in message::netlink_messages(&slot[..n]) {
+                    let gap = match message::parse_event_message(message) {
+                        Ok((seq, event)) => inbox.push(seq, event),
+                        Err(MessageError::Truncated) => {
+                            lost = true;
+                            continue;
+                        }
+                        // acknowledgements, and events this crate doesn't know
+                        Err(_) => match message::parse_sequence(message) {
+                            Ok((cpu, seq)) => inbox.advance(cpu, seq),
+                            Err(_) => continue,
+                        },
+                    };
+
+                    if gap {
+                        self.overflows.fetch_add(1, Ordering::Relaxed);
                     }
+                }
 
-                    return parse_netlink_event_message(buf).ok_or(ErrorKind::InvalidData.into());
+                if lost {
+                    self.overflows.fetch_add(1, Ordering::Relaxed);
+                    inbox.push_lost();
                 }
-                Ok(Err(e)) => return Err(e),
-                // TODO: it accutally impossible to reach this branch, didn't set NON_BLOCK flag
+
+                received += 1;
+            }
+
+            sockets.cursor = (i + 1) % len;
+            ready = true;
+        }
+
+        match ready {
+            true => Ok(()),
+            false => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+/// [`NetlinkConnection`] registered with the tokio reactor once, for its whole lifetime
+#[cfg(feature = "async-netlink")]
+#[derive(Debug)]
+pub(super) struct AsyncNetlinkConnection(AsyncFd<NetlinkConnection>);
+
+#[cfg(feature = "async-netlink")]
+impl AsyncNetlinkConnection {
+    /// Must be called within a tokio runtime
+    pub(super) fn new(netlink: NetlinkConnection) -> Result<Self> {
+        // SAFETY: the connection owns its fd and is moved into AsyncFd
+        let netlink = unsafe { AsyncFd::register_with_interest(netlink, Interest::READABLE) }?;
+
+        Ok(Self(netlink))
+    }
+
+    // WARNING: multiple reader in the same time may cause unwanted behavior.
+    pub(super) fn poll_read_event(&self, cx: &mut Context<'_>) -> Poll<Result<ProcEvent>> {
+        self.poll_take(cx, NetlinkConnection::try_read_event)
+    }
+
+    /// See [`NetlinkConnection::read_events`]
+    pub(super) fn poll_read_events(&self, cx: &mut Context<'_>) -> Poll<Result<Vec<ProcEvent>>> {
+        self.poll_take(cx, NetlinkConnection::try_read_events)
+    }
+
+    fn poll_take<T>(
+        &self,
+        cx: &mut Context<'_>,
+        try_read: fn(&NetlinkConnection) -> Result<T>,
+    ) -> Poll<Result<T>> {
+        loop {
+            let mut guard = ready!(self.0.poll_read_ready(cx))?;
+
+            // readiness is cleared once every socket is drained and nothing is queued
+            match guard.try_io(|inner| try_read(inner.get_ref())) {
+                Ok(r) => return Poll::Ready(r),
                 Err(_would_block) => continue,
             }
         }
     }
+
+    pub(super) async fn read_events(&self) -> Result<Vec<ProcEvent>> {
+        poll_fn(|cx| self.poll_read_events(cx)).await
+    }
 }
 
+#[cfg(feature = "async-netlink")]
+impl Deref for AsyncNetlinkConnection {
+    type Target = NetlinkConnection;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.get_ref()
+    }
+}
+
+/// Readable when any of the sockets is
 impl AsFd for NetlinkConnection {
-    fn as_fd(&self) -> BorrowedFd {
-        self.fd.as_fd()
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.epoll.as_fd()
+    }
+}
+
+impl AsRawFd for NetlinkConnection {
+    fn as_raw_fd(&self) -> RawFd {
+        self.epoll.as_raw_fd()
+    }
+}
+
+/// Wait for the acknowledgement of the control message sent with `ack`
+///
+/// The kernel doesn't acknowledge LISTEN without `CAP_NET_ADMIN` if nobody else is listening,
+/// nor from other namespaces than the initial ones, so it's a [`ErrorKind::PermissionDenied`]
+/// if nothing arrives.
+fn wait_ack(fd: &OwnedFd, ack: u32) -> Result<()> {
+    let deadline = Instant::now() + ACK_TIMEOUT;
+    let mut buf = [0u8; NL_MESSAGE_BASE_SIZE + PROC_EVENT_SIZE];
+
+    loop {
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let mut fds = [event::PollFd::new(fd, event::PollFlags::IN)];
+
+        if event::poll(&mut fds, timeout.as_millis() as i32)? == 0 {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "proc connector LISTEN is not acknowledged, \
+                 CAP_NET_ADMIN in the initial user and pid namespaces is required",
+            ));
+        }
+
+        let n = match net::recv(fd, &mut buf, RecvFlags::DONTWAIT) {
+            Ok(n) => n,
+            // events queued before the ack filter was attached overflowed
+            Err(Errno::AGAIN | Errno::NOBUFS) => continue,
+            Err(e) => return Err(e.into()),
+        };
+
+        match message::parse_ack_message(&buf[..n.min(buf.len())], ack) {
+            Some(0) => return Ok(()),
+            Some(err) => return Err(Error::from_raw_os_error(err as i32)),
+            // queued before the ack filter was attached
+            None => continue,
+        }
+    }
+}
+
+/// Netlink port id the socket is bound to
+fn local_port(fd: &OwnedFd) -> Result<u32> {
+    // SAFETY: all zeros is a valid sockaddr_nl
+    let mut sa_nl = unsafe { std::mem::zeroed::<netlink::sockaddr_nl>() };
+    let mut len = size_of::<netlink::sockaddr_nl>() as libc::socklen_t;
+
+    // SAFETY: sa_nl is valid to write for len bytes
+    let ret = unsafe {
+        libc::getsockname(
+            fd.as_raw_fd(),
+            addr_of_mut!(sa_nl).cast::<sockaddr>(),
+            &mut len,
+        )
+    };
+
+    match ret {
+        -1 => Err(Error::last_os_error()),
+        _ => Ok(sa_nl.nl_pid),
     }
 }
 
-fn make_netlink_control_message(control_op: proc_cn_mcast_op) -> [u8; NL_MESSAGE_MCAST_SIZE] {
-    let self_pid = process::getpid().as_raw_nonzero().get();
+/// Linux 6.6+, older kernels silently drop `proc_input`, so a probe would wait for a whole
+/// [`ACK_TIMEOUT`] there. A wrong guess only costs wakeups, the filter drops other kinds anyway.
+fn proc_input_supported() -> bool {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+
+    *SUPPORTED.get_or_init(|| kernel_version().is_some_and(|x| x >= (6, 6)))
+}
+
+/// Major and minor version of the running kernel
+fn kernel_version() -> Option<(u32, u32)> {
+    // SAFETY: utsname is plain bytes, all zeros is valid
+    let mut uts = unsafe { std::mem::zeroed::<libc::utsname>() };
+
+    // SAFETY: uts is a valid utsname to write
+    if unsafe { libc::uname(&mut uts) } == -1 {
+        return None;
+    }
+
+    // SAFETY: the kernel NUL terminates the release
+    let release = unsafe { CStr::from_ptr(uts.release.as_ptr()) }
+        .to_str()
+        .ok()?;
+
+    // like "6.6.0-rc1"
+    let mut numbers = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|x| x.parse().ok());
+
+    Some((numbers.next()??, numbers.next()??))
+}
+
+/// Goes beyond `net.core.rmem_max` if permitted
+fn set_recv_buffer_size(fd: &OwnedFd, size: usize) -> Result<()> {
+    match sockopt::set_socket_recv_buffer_size_force(fd, size) {
+        Err(Errno::PERM) => Ok(sockopt::set_socket_recv_buffer_size(fd, size)?),
+        r => Ok(r?),
+    }
+}
+
+/// Receive datagrams into `slots` without blocking, their lengths and whether they were
+/// truncated
+fn recv_batch(
+    fd: &OwnedFd,
+    slots: &mut [[u8; RECV_SLOT_SIZE]],
+) -> rustix::io::Result<Vec<(usize, bool)>> {
+    // SAFETY: all zeros is a valid iovec and mmsghdr
+    let mut iovecs = unsafe { std::mem::zeroed::<[libc::iovec; RECV_BATCH]>() };
+    // SAFETY: as above
+    let mut msgs = unsafe { std::mem::zeroed::<[libc::mmsghdr; RECV_BATCH]>() };
+    let len = slots.len().min(RECV_BATCH);
+
+    for ((slot, iovec), msg) in slots.iter_mut().zip(&mut iovecs).zip(&mut msgs) {
+        iovec.iov_base = slot.as_mut_ptr().cast();
+        iovec.iov_len = slot.len();
+        msg.msg_hdr.msg_iov = iovec;
+        msg.msg_hdr.msg_iovlen = 1;
+    }
+
+    // SAFETY: the first `len` headers point to live slots, the timeout is optional
+    let n = unsafe {
+        libc::recvmmsg(
+            fd.as_raw_fd(),
+            msgs.as_mut_ptr(),
+            len as _,
+            libc::MSG_DONTWAIT,
+            ptr::null_mut(),
+        )
+    };
+
+    if n == -1 {
+        let errno = Error::last_os_error().raw_os_error().unwrap_or_default();
+        return Err(Errno::from_raw_os_error(errno));
+    }
+
+    let datagrams = msgs[..n as usize].iter().map(|x| {
+        let truncated = x.msg_hdr.msg_flags & libc::MSG_TRUNC != 0;
+        (x.msg_len as usize, truncated)
+    });
+
+    Ok(datagrams.collect())
+}
+
+/// The message sent from `port` and its length, the extended `proc_input` form if `event_type`
+/// is given
+fn make_netlink_control_message(
+    port: u32,
+    control_op: proc_cn_mcast_op,
+    ack: u32,
+    event_type: Option<u32>,
+) -> ([u8; NL_MESSAGE_INPUT_SIZE], usize) {
+    let (len, data_len) = match event_type {
+        Some(_) => (NL_MESSAGE_INPUT_SIZE, PROC_INPUT_SIZE),
+        None => (NL_MESSAGE_MCAST_SIZE, MCAST_OP_SIZE),
+    };
 
     // send call needn't alignment, stack array is fine
-    let mut buf = [0u8; NL_MESSAGE_MCAST_SIZE];
+    let mut buf = [0u8; NL_MESSAGE_INPUT_SIZE];
 
     // headers
     {
@@ -169,11 +1030,11 @@ fn make_netlink_control_message(control_op: proc_cn_mcast_op) -> [u8; NL_MESSAGE
         // and it's length is known and suitable for writing
         unsafe {
             nlh_ptr.write_unaligned(netlink::nlmsghdr {
-                nlmsg_len: NL_MESSAGE_MCAST_SIZE as u32,
+                nlmsg_len: len as u32,
                 nlmsg_type: NLMSG_DONE as u16,
                 nlmsg_flags: 0,
                 nlmsg_seq: 0,
-                nlmsg_pid: self_pid as u32,
+                nlmsg_pid: port,
             });
         }
     }
@@ -192,8 +1053,8 @@ fn make_netlink_control_message(control_op: proc_cn_mcast_op) -> [u8; NL_MESSAGE
                     val: CN_VAL_PROC,
                 },
                 seq: 0,
-                ack: 0,
-                len: MCAST_OP_SIZE as u16,
+                ack,
+                len: data_len as u16,
                 flags: 0,
                 data: IncompleteArray::new(),
             });
@@ -203,48 +1064,24 @@ fn make_netlink_control_message(control_op: proc_cn_mcast_op) -> [u8; NL_MESSAGE
     // msg data
     {
         // SAFETY: structure layout is known and suitable for writing, no overflow
-        let data_ptr =
-            unsafe { buf.as_mut_ptr().add(NL_MESSAGE_BASE_SIZE) }.cast::<proc_cn_mcast_op>();
-
-        // SAFETY: data_ptr is a valid pointer to a c_int
-        unsafe { data_ptr.write_unaligned(control_op as proc_cn_mcast_op) };
-    }
-
-    buf
-}
+        let data_ptr = unsafe { buf.as_mut_ptr().add(NL_MESSAGE_BASE_SIZE) };
 
-fn parse_netlink_event_message(buf: &[u8; NL_CONNECTOR_MAX_MSG_SIZE]) -> Option<Pid> {
-    let nlh_ptr = buf.as_ptr();
-    // SAFETY: structure layout is known and suitable for writing, no overflow
-    let cn_msg_ptr = unsafe { nlh_ptr.add(NLMSGHDR_SIZE) };
-    // SAFETY: structure layout is known and suitable for writing, no overflow
-    let proc_event_ptr = unsafe { cn_msg_ptr.add(CN_MSG_SIZE) };
-
-    // SAFETY: nlh_ptr is a valid pointer to a nlmsghdr
-    // and it's length is known and suitable for reading
-    let nlh = unsafe { nlh_ptr.cast::<nlmsghdr>().read_unaligned() };
-    // // do we need to check nlh.nlmsg_type == NLMSG_ERROR and return Result?
-    // if nlh.nlmsg_type == NLMSG_ERROR as u16 {
-    //     return Ok(());
-    // }
-    if nlh.nlmsg_type != NLMSG_DONE as u16 {
-        return None;
-    }
-
-    // SAFETY: cn_msg_ptr is a valid pointer to a cn_msg
-    // and it's length is known and suitable for reading
-    let cn_msg = unsafe { cn_msg_ptr.cast::<cn_msg>().read_unaligned() };
-    if cn_msg.len as usize != PROC_EVENT_SIZE {
-        return None;
-    }
-
-    // SAFETY: event is a valid pointer to a proc_event
-    // and it's length is known and suitable for reading
-    // DO NOT read cn_msg.data, it's not a valid stack array after copy
-    let proc_event = unsafe { proc_event_ptr.cast::<proc_event>().read_unaligned() };
-    if !matches!(proc_event.what, proc_cn_event::PROC_EVENT_EXIT) {
-        return None;
+        match event_type {
+            // SAFETY: data_ptr is a valid pointer to a proc_input
+            Some(event_type) => unsafe {
+                data_ptr.cast::<proc_input>().write_unaligned(proc_input {
+                    mcast_op: control_op,
+                    event_type,
+                })
+            },
+            // SAFETY: data_ptr is a valid pointer to a c_int
+            None => unsafe {
+                data_ptr
+                    .cast::<proc_cn_mcast_op>()
+                    .write_unaligned(control_op as proc_cn_mcast_op)
+            },
+        }
     }
 
-    Pid::from_raw(proc_event.event_data.process_tgid as i32)
+    (buf, len)
 }
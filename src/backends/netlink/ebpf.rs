@@ -0,0 +1,315 @@
+/// eBPF socket filter with a hash map of pids
+use std::{
+    io::{Error, Result},
+    mem::{offset_of, size_of},
+    ptr::addr_of,
+};
+
+use linux_raw_sys::netlink::NLMSG_DONE;
+use rustix::{
+    fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+    process::Pid,
+};
+
+use super::binding::{
+    cb_id, cn_msg, exit_proc_event, nlmsghdr, proc_event, CN_IDX_PROC, CN_VAL_PROC,
+};
+
+/// Most pids held by the map, buckets are allocated for all of them
+const MAX_MAP_PIDS: u32 = 1 << 16;
+
+// bpf(2) commands
+const BPF_MAP_CREATE: libc::c_int = 0;
+const BPF_MAP_UPDATE_ELEM: libc::c_int = 2;
+const BPF_MAP_DELETE_ELEM: libc::c_int = 3;
+const BPF_PROG_LOAD: libc::c_int = 5;
+
+const BPF_MAP_TYPE_HASH: u32 = 1;
+const BPF_PROG_TYPE_SOCKET_FILTER: u32 = 1;
+/// Entries are allocated on insertion
+const BPF_F_NO_PREALLOC: u32 = 1;
+const BPF_FUNC_MAP_LOOKUP_ELEM: i32 = 1;
+/// `imm` of a 64 bit immediate load is a map fd
+const BPF_PSEUDO_MAP_FD: u8 = 1;
+
+// instruction classes
+const BPF_LD: u8 = 0x00;
+const BPF_STX: u8 = 0x03;
+const BPF_ALU: u8 = 0x04;
+const BPF_JMP: u8 = 0x05;
+const BPF_JMP32: u8 = 0x06;
+const BPF_ALU64: u8 = 0x07;
+
+// sizes and modes
+const BPF_W: u8 = 0x00;
+const BPF_H: u8 = 0x08;
+const BPF_DW: u8 = 0x18;
+const BPF_IMM: u8 = 0x00;
+const BPF_ABS: u8 = 0x20;
+const BPF_MEM: u8 = 0x60;
+
+// operations
+const BPF_ADD: u8 = 0x00;
+const BPF_JA: u8 = 0x00;
+const BPF_JEQ: u8 = 0x10;
+const BPF_JSET: u8 = 0x40;
+const BPF_JNE: u8 = 0x50;
+const BPF_CALL: u8 = 0x80;
+const BPF_EXIT: u8 = 0x90;
+const BPF_MOV: u8 = 0xb0;
+const BPF_END: u8 = 0xd0;
+
+// sources
+const BPF_K: u8 = 0x00;
+const BPF_X: u8 = 0x08;
+const BPF_TO_BE: u8 = 0x08;
+
+const R0: u8 = 0;
+const R1: u8 = 1;
+const R2: u8 = 2;
+const R6: u8 = 6;
+const R10: u8 = 10;
+
+const DROP: i32 = 0x0;
+const ACCEPT: i32 = 0xffffffff_u32 as i32;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct bpf_insn {
+    code: u8,
+    /// `dst_reg:4` and `src_reg:4` bit fields
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+impl bpf_insn {
+    const fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Self {
+        #[cfg(target_endian = "little")]
+        let regs = dst | src << 4;
+        #[cfg(target_endian = "big")]
+        let regs = dst << 4 | src;
+
+        Self {
+            code,
+            regs,
+            off,
+            imm,
+        }
+    }
+}
+
+// prefixes of `union bpf_attr` for each command, the kernel zeroes the rest
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct bpf_map_create_attr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct bpf_map_elem_attr {
+    map_fd: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+struct bpf_prog_load_attr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+}
+
+/// Socket filter passing events about the tgids in its map.
+///
+/// Updating the map changes the filter of every socket it's attached to, without
+/// loading the program again.
+#[derive(Debug)]
+pub(super) struct PidMapFilter {
+    map: OwnedFd,
+    prog: OwnedFd,
+}
+
+impl PidMapFilter {
+    /// Fails if the kernel doesn't permit eBPF, usually without `CAP_BPF`
+    pub(super) fn new(kinds: u32) -> Result<Self> {
+        let attr = bpf_map_create_attr {
+            map_type: BPF_MAP_TYPE_HASH,
+            key_size: size_of::<u32>() as u32,
+            value_size: size_of::<u8>() as u32,
+            max_entries: MAX_MAP_PIDS,
+            map_flags: BPF_F_NO_PREALLOC,
+        };
+        // SAFETY: the attribute is valid and the returned fd is owned by nobody else
+        let map = unsafe { bpf(BPF_MAP_CREATE, &attr).map(|fd| OwnedFd::from_raw_fd(fd))? };
+
+        let insns = assembly_program(kinds, map.as_fd());
+        let attr = bpf_prog_load_attr {
+            prog_type: BPF_PROG_TYPE_SOCKET_FILTER,
+            insn_cnt: insns.len() as u32,
+            insns: insns.as_ptr() as u64,
+            license: c"Apache-2.0".as_ptr() as u64,
+        };
+        // SAFETY: the attribute points to live instructions, the returned fd is owned by nobody else
+        let prog = unsafe { bpf(BPF_PROG_LOAD, &attr).map(|fd| OwnedFd::from_raw_fd(fd))? };
+
+        Ok(Self { map, prog })
+    }
+
+    /// Replace the filter of the socket with this one
+    pub(super) fn attach(&self, fd: BorrowedFd) -> Result<()> {
+        let prog = self.prog.as_raw_fd();
+
+        // SAFETY: the option value is a valid c_int
+        let ret = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_ATTACH_BPF,
+                addr_of!(prog).cast(),
+                size_of::<libc::c_int>() as _,
+            )
+        };
+
+        match ret {
+            -1 => Err(Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    pub(super) fn insert(&self, pid: Pid) -> Result<()> {
+        let key = pid.as_raw_nonzero().get() as u32;
+        let value = 1u8;
+
+        let attr = bpf_map_elem_attr {
+            map_fd: self.map.as_raw_fd() as u32,
+            key: addr_of!(key) as u64,
+            value: addr_of!(value) as u64,
+            flags: 0, // BPF_ANY
+        };
+        // SAFETY: the attribute points to a live key and value of the map's sizes
+        unsafe { bpf(BPF_MAP_UPDATE_ELEM, &attr) }?;
+
+        Ok(())
+    }
+
+    /// Missing pids are ignored
+    pub(super) fn remove(&self, pid: Pid) -> Result<()> {
+        let key = pid.as_raw_nonzero().get() as u32;
+
+        let attr = bpf_map_elem_attr {
+            map_fd: self.map.as_raw_fd() as u32,
+            key: addr_of!(key) as u64,
+            value: 0,
+            flags: 0,
+        };
+        // SAFETY: the attribute points to a live key of the map's size
+        match unsafe { bpf(BPF_MAP_DELETE_ELEM, &attr) } {
+            Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+            r => r.map(drop),
+        }
+    }
+}
+
+/// # Safety
+///
+/// `attr` must be valid for `cmd`, pointers in it must be live during the call.
+unsafe fn bpf<T>(cmd: libc::c_int, attr: &T) -> Result<i32> {
+    let ret = libc::syscall(libc::SYS_bpf, cmd, attr as *const T, size_of::<T>());
+
+    match ret {
+        -1 => Err(Error::last_os_error()),
+        fd => Ok(fd as i32),
+    }
+}
+
+// same checks as the cBPF filter, then the tgid is looked up in the map
+fn assembly_program(kinds: u32, map: BorrowedFd) -> Vec<bpf_insn> {
+    use bpf_insn as I;
+
+    /* legacy packet loads convert from network byte order, like cBPF ones */
+    let load = |size: u8, offset: usize| I::new(BPF_LD | size | BPF_ABS, 0, 0, 0, offset as i32);
+    /* jump to the drop at the end, the offset is patched below */
+    const TO_DROP: i16 = i16::MIN;
+    let drop_if = |op: u8, value: u32| I::new(BPF_JMP32 | op | BPF_K, R0, 0, TO_DROP, value as i32);
+
+    let mut prog = vec![
+        /* packet loads need the context in R6 */
+        I::new(BPF_ALU64 | BPF_MOV | BPF_X, R6, R1, 0, 0),
+        /* check message's type is NLMSG_DONE */
+        load(BPF_H, offset_of!(nlmsghdr, nlmsg_type)),
+        drop_if(BPF_JNE, (NLMSG_DONE as u16).to_be() as u32),
+        /* check message comes from the kernel */
+        load(BPF_W, offset_of!(nlmsghdr, nlmsg_pid)),
+        drop_if(BPF_JNE, 0),
+        /* check it's a proc connector event */
+        load(
+            BPF_W,
+            size_of::<nlmsghdr>() + offset_of!(cn_msg, id) + offset_of!(cb_id, idx),
+        ),
+        drop_if(BPF_JNE, CN_IDX_PROC.to_be()),
+        load(
+            BPF_W,
+            size_of::<nlmsghdr>() + offset_of!(cn_msg, id) + offset_of!(cb_id, val),
+        ),
+        drop_if(BPF_JNE, CN_VAL_PROC.to_be()),
+        /* check the event kind is subscribed */
+        load(
+            BPF_W,
+            size_of::<nlmsghdr>() + size_of::<cn_msg>() + offset_of!(proc_event, what),
+        ),
+        I::new(BPF_JMP32 | BPF_JSET | BPF_K, R0, 0, 1, kinds.to_be() as i32),
+        I::new(BPF_JMP | BPF_JA, 0, 0, TO_DROP, 0),
+        /* load the tgid and restore the byte order of the map key */
+        load(
+            BPF_W,
+            size_of::<nlmsghdr>()
+                + size_of::<cn_msg>()
+                + offset_of!(proc_event, event_data)
+                + offset_of!(exit_proc_event, process_tgid),
+        ),
+        I::new(BPF_ALU | BPF_END | BPF_TO_BE, R0, 0, 0, 32),
+        I::new(BPF_STX | BPF_MEM | BPF_W, R10, R0, -4, 0),
+        /* lookup the key on the stack */
+        I::new(BPF_ALU64 | BPF_MOV | BPF_X, R2, R10, 0, 0),
+        I::new(BPF_ALU64 | BPF_ADD | BPF_K, R2, 0, 0, -4),
+        I::new(
+            BPF_LD | BPF_DW | BPF_IMM,
+            R1,
+            BPF_PSEUDO_MAP_FD,
+            0,
+            map.as_raw_fd(),
+        ),
+        I::new(0, 0, 0, 0, 0), // upper half of the 64 bit immediate
+        I::new(BPF_JMP | BPF_CALL, 0, 0, 0, BPF_FUNC_MAP_LOOKUP_ELEM),
+        /* null checks of the result must be 64 bit */
+        I::new(BPF_JMP | BPF_JEQ | BPF_K, R0, 0, TO_DROP, 0),
+        /* message is sent to user space */
+        I::new(BPF_ALU | BPF_MOV | BPF_K, R0, 0, 0, ACCEPT),
+        I::new(BPF_JMP | BPF_EXIT, 0, 0, 0, 0),
+    ];
+
+    let drop = prog.len();
+    for (pc, insn) in prog.iter_mut().enumerate() {
+        if insn.off == TO_DROP {
+            insn.off = (drop - pc - 1) as i16;
+        }
+    }
+
+    prog.extend([
+        I::new(BPF_ALU | BPF_MOV | BPF_K, R0, 0, 0, DROP),
+        I::new(BPF_JMP | BPF_EXIT, 0, 0, 0, 0),
+    ]);
+
+    prog
+}
@@ -0,0 +1,316 @@
+/// Typed proc connector events
+use std::mem::offset_of;
+
+use rustix::process::Pid;
+
+use super::{
+    binding::{
+        comm_proc_event, coredump_proc_event, exec_proc_event, exit_proc_event, fork_proc_event,
+        id_proc_event, proc_cn_event, proc_event, ptrace_proc_event, sid_proc_event,
+    },
+    message::{read, read_u32, read_u64, MessageError},
+};
+use crate::{utils::procfs, ExitInfo};
+
+bitflags::bitflags! {
+    /// Kinds of [`ProcEvent`] to subscribe to.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct ProcEventKinds: u32 {
+        const FORK = proc_cn_event::PROC_EVENT_FORK as u32;
+        const EXEC = proc_cn_event::PROC_EVENT_EXEC as u32;
+        const UID = proc_cn_event::PROC_EVENT_UID as u32;
+        const GID = proc_cn_event::PROC_EVENT_GID as u32;
+        const SID = proc_cn_event::PROC_EVENT_SID as u32;
+        const PTRACE = proc_cn_event::PROC_EVENT_PTRACE as u32;
+        const COMM = proc_cn_event::PROC_EVENT_COMM as u32;
+        const COREDUMP = proc_cn_event::PROC_EVENT_COREDUMP as u32;
+        const EXIT = proc_cn_event::PROC_EVENT_EXIT as u32;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ForkEvent {
+    pub cpu: u32,
+    /// Nanoseconds since system boot
+    pub timestamp_ns: u64,
+    pub parent_pid: Option<Pid>,
+    pub parent_tgid: Option<Pid>,
+    pub child_pid: Pid,
+    pub child_tgid: Pid,
+}
+
+impl ForkEvent {
+    /// Whether a new thread was created rather than a new process.
+    #[inline]
+    pub fn is_thread(&self) -> bool {
+        self.child_pid != self.child_tgid
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExecEvent {
+    pub cpu: u32,
+    /// Nanoseconds since system boot
+    pub timestamp_ns: u64,
+    pub pid: Pid,
+    pub tgid: Pid,
+}
+
+/// Credential change, carries uids for [`ProcEvent::Uid`] and gids for [`ProcEvent::Gid`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdEvent {
+    pub cpu: u32,
+    /// Nanoseconds since system boot
+    pub timestamp_ns: u64,
+    pub pid: Pid,
+    pub tgid: Pid,
+    pub real: u32,
+    pub effective: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SidEvent {
+    pub cpu: u32,
+    /// Nanoseconds since system boot
+    pub timestamp_ns: u64,
+    pub pid: Pid,
+    pub tgid: Pid,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtraceEvent {
+    pub cpu: u32,
+    /// Nanoseconds since system boot
+    pub timestamp_ns: u64,
+    pub pid: Pid,
+    pub tgid: Pid,
+    /// `None` on detach
+    pub tracer_pid: Option<Pid>,
+    pub tracer_tgid: Option<Pid>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommEvent {
+    pub cpu: u32,
+    /// Nanoseconds since system boot
+    pub timestamp_ns: u64,
+    pub pid: Pid,
+    pub tgid: Pid,
+    /// NUL padded
+    pub comm: [u8; 16],
+}
+
+impl CommEvent {
+    /// New name of the thread, without NUL padding.
+    pub fn name(&self) -> &[u8] {
+        let len = self
+            .comm
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(self.comm.len());
+        &self.comm[..len]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoredumpEvent {
+    pub cpu: u32,
+    /// Nanoseconds since system boot
+    pub timestamp_ns: u64,
+    pub pid: Pid,
+    pub tgid: Pid,
+    pub parent_pid: Option<Pid>,
+    pub parent_tgid: Option<Pid>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitEvent {
+    pub cpu: u32,
+    /// Nanoseconds since system boot
+    pub timestamp_ns: u64,
+    pub pid: Pid,
+    pub tgid: Pid,
+    /// Raw wait status
+    pub exit_code: u32,
+    /// Signal sent to the parent, -1 for threads other than the group leader
+    pub exit_signal: i32,
+    pub parent_pid: Option<Pid>,
+    pub parent_tgid: Option<Pid>,
+}
+
+impl ExitEvent {
+    /// Exit information about the thread group.
+    pub fn info(&self) -> ExitInfo {
+        ExitInfo {
+            pid: self.tgid,
+            status: self.exit_code as i32,
+            exit_signal: Some(self.exit_signal).filter(|&s| s >= 0),
+            parent_pid: self.parent_tgid,
+            timestamp_ns: Some(self.timestamp_ns),
+        }
+    }
+
+    /// Whether the whole thread group is gone with this exit.
+    ///
+    /// The kernel reports the exit of every thread, and the leader may exit before the others.
+    /// Remaining threads are looked up in procfs, so the answer is only meaningful right after
+    /// the event is received. Without procfs only the exit of the leader counts.
+    pub fn is_group_exit(&self) -> bool {
+        match procfs::thread_group_running(self.tgid) {
+            Ok(running) => !running,
+            Err(_) => self.pid == self.tgid,
+        }
+    }
+}
+
+/// Event reported by the kernel proc connector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ProcEvent {
+    Fork(ForkEvent),
+    Exec(ExecEvent),
+    Uid(IdEvent),
+    Gid(IdEvent),
+    Sid(SidEvent),
+    Ptrace(PtraceEvent),
+    Comm(CommEvent),
+    Coredump(CoredumpEvent),
+    Exit(ExitEvent),
+}
+
+impl ProcEvent {
+    /// Decode the `proc_event` of a message, `what` is checked before the union is read
+    pub(super) fn parse(event: &[u8]) -> Result<Self, MessageError> {
+        let what = read_u32(event, offset_of!(proc_event, what))?;
+        let cpu = read_u32(event, offset_of!(proc_event, cpu))?;
+        let timestamp_ns = read_u64(event, offset_of!(proc_event, timestamp_ns))?;
+
+        // fields of the union member
+        let field = |offset: usize| read_u32(event, offset_of!(proc_event, event_data) + offset);
+        let pid = |offset: usize| field(offset).map(|x| Pid::from_raw(x as i32));
+        let required = |offset: usize| -> Result<Pid, MessageError> {
+            pid(offset)?.ok_or(MessageError::Invalid)
+        };
+
+        let r = match proc_cn_event::from_raw(what).ok_or(MessageError::Unknown(what))? {
+            proc_cn_event::PROC_EVENT_FORK => Self::Fork(ForkEvent {
+                cpu,
+                timestamp_ns,
+                parent_pid: pid(offset_of!(fork_proc_event, parent_pid))?,
+                parent_tgid: pid(offset_of!(fork_proc_event, parent_tgid))?,
+                child_pid: required(offset_of!(fork_proc_event, child_pid))?,
+                child_tgid: required(offset_of!(fork_proc_event, child_tgid))?,
+            }),
+            proc_cn_event::PROC_EVENT_EXEC => Self::Exec(ExecEvent {
+                cpu,
+                timestamp_ns,
+                pid: required(offset_of!(exec_proc_event, process_pid))?,
+                tgid: required(offset_of!(exec_proc_event, process_tgid))?,
+            }),
+            what @ (proc_cn_event::PROC_EVENT_UID | proc_cn_event::PROC_EVENT_GID) => {
+                let id = IdEvent {
+                    cpu,
+                    timestamp_ns,
+                    pid: required(offset_of!(id_proc_event, process_pid))?,
+                    tgid: required(offset_of!(id_proc_event, process_tgid))?,
+                    real: field(offset_of!(id_proc_event, r))?,
+                    effective: field(offset_of!(id_proc_event, e))?,
+                };
+                match what {
+                    proc_cn_event::PROC_EVENT_UID => Self::Uid(id),
+                    _ => Self::Gid(id),
+                }
+            }
+            proc_cn_event::PROC_EVENT_SID => Self::Sid(SidEvent {
+                cpu,
+                timestamp_ns,
+                pid: required(offset_of!(sid_proc_event, process_pid))?,
+                tgid: required(offset_of!(sid_proc_event, process_tgid))?,
+            }),
+            proc_cn_event::PROC_EVENT_PTRACE => Self::Ptrace(PtraceEvent {
+                cpu,
+                timestamp_ns,
+                pid: required(offset_of!(ptrace_proc_event, process_pid))?,
+                tgid: required(offset_of!(ptrace_proc_event, process_tgid))?,
+                tracer_pid: pid(offset_of!(ptrace_proc_event, tracer_pid))?,
+                tracer_tgid: pid(offset_of!(ptrace_proc_event, tracer_tgid))?,
+            }),
+            proc_cn_event::PROC_EVENT_COMM => Self::Comm(CommEvent {
+                cpu,
+                timestamp_ns,
+                pid: required(offset_of!(comm_proc_event, process_pid))?,
+                tgid: required(offset_of!(comm_proc_event, process_tgid))?,
+                comm: read(
+                    event,
+                    offset_of!(proc_event, event_data) + offset_of!(comm_proc_event, comm),
+                )?,
+            }),
+            proc_cn_event::PROC_EVENT_COREDUMP => Self::Coredump(CoredumpEvent {
+                cpu,
+                timestamp_ns,
+                pid: required(offset_of!(coredump_proc_event, process_pid))?,
+                tgid: required(offset_of!(coredump_proc_event, process_tgid))?,
+                parent_pid: pid(offset_of!(coredump_proc_event, parent_pid))?,
+                parent_tgid: pid(offset_of!(coredump_proc_event, parent_tgid))?,
+            }),
+            proc_cn_event::PROC_EVENT_EXIT => Self::Exit(ExitEvent {
+                cpu,
+                timestamp_ns,
+                pid: required(offset_of!(exit_proc_event, process_pid))?,
+                tgid: required(offset_of!(exit_proc_event, process_tgid))?,
+                exit_code: field(offset_of!(exit_proc_event, exit_code))?,
+                exit_signal: field(offset_of!(exit_proc_event, exit_signal))? as i32,
+                parent_pid: pid(offset_of!(exit_proc_event, parent_pid))?,
+                parent_tgid: pid(offset_of!(exit_proc_event, parent_tgid))?,
+            }),
+            // acknowledgements, and a mask bit which is never reported on its own
+            proc_cn_event::PROC_EVENT_NONE | proc_cn_event::PROC_EVENT_NONZERO_EXIT => {
+                return Err(MessageError::Unknown(what))
+            }
+        };
+
+        Ok(r)
+    }
+
+    /// Thread group the event is about, the parent's one for [`ProcEvent::Fork`].
+    pub fn tgid(&self) -> Option<Pid> {
+        match self {
+            Self::Fork(e) => e.parent_tgid,
+            Self::Exec(e) => Some(e.tgid),
+            Self::Uid(e) | Self::Gid(e) => Some(e.tgid),
+            Self::Sid(e) => Some(e.tgid),
+            Self::Ptrace(e) => Some(e.tgid),
+            Self::Comm(e) => Some(e.tgid),
+            Self::Coredump(e) => Some(e.tgid),
+            Self::Exit(e) => Some(e.tgid),
+        }
+    }
+
+    /// CPU the event was reported on.
+    pub fn cpu(&self) -> u32 {
+        match self {
+            Self::Fork(e) => e.cpu,
+            Self::Exec(e) => e.cpu,
+            Self::Uid(e) | Self::Gid(e) => e.cpu,
+            Self::Sid(e) => e.cpu,
+            Self::Ptrace(e) => e.cpu,
+            Self::Comm(e) => e.cpu,
+            Self::Coredump(e) => e.cpu,
+            Self::Exit(e) => e.cpu,
+        }
+    }
+
+    /// Nanoseconds since system boot when the event happened.
+    pub fn timestamp_ns(&self) -> u64 {
+        match self {
+            Self::Fork(e) => e.timestamp_ns,
+            Self::Exec(e) => e.timestamp_ns,
+            Self::Uid(e) | Self::Gid(e) => e.timestamp_ns,
+            Self::Sid(e) => e.timestamp_ns,
+            Self::Ptrace(e) => e.timestamp_ns,
+            Self::Comm(e) => e.timestamp_ns,
+            Self::Coredump(e) => e.timestamp_ns,
+            Self::Exit(e) => e.timestamp_ns,
+        }
+    }
+}
@@ -0,0 +1,94 @@
+/// execve waiter
+use std::{
+    ffi::OsString,
+    io::{Error, Result},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use rustix::process::Pid;
+
+use super::{
+    event::{ExecEvent, ProcEvent, ProcEventKinds},
+    stream::ProcEventStream,
+};
+use crate::utils::procfs;
+
+/// Program a process is running after `execve`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExecInfo {
+    pub pid: Pid,
+    /// Nanoseconds since system boot when the process exec'd
+    pub timestamp_ns: u64,
+    /// Target of `/proc/<pid>/exe`
+    pub exe: PathBuf,
+    pub cmdline: Vec<OsString>,
+}
+
+impl ExecInfo {
+    fn capture(event: &ExecEvent) -> Result<Self> {
+        Ok(Self {
+            pid: event.tgid,
+            timestamp_ns: event.timestamp_ns,
+            exe: procfs::exe(event.tgid)?,
+            cmdline: procfs::cmdline(event.tgid)?,
+        })
+    }
+}
+
+/// Wait for `pid` to perform `execve`.
+///
+/// Fails with `ESRCH` if the process exits before that. Requires `CAP_NET_ADMIN`.
+pub fn wait_exec(pid: Pid, timeout: Option<Duration>) -> Result<ExecInfo> {
+    let deadline = timeout.map(|x| Instant::now() + x);
+    let mut stream = ProcEventStream::with_pids(exec_kinds(), &[pid])?;
+
+    check_running(pid)?;
+
+    loop {
+        let timeout = deadline.map(|x| x.saturating_duration_since(Instant::now()));
+
+        if let Some(r) = handle_event(pid, stream.recv(timeout)?) {
+            return r;
+        }
+    }
+}
+
+/// Async version of [`wait_exec`].
+#[cfg(feature = "async-netlink")]
+pub async fn wait_exec_async(pid: Pid) -> Result<ExecInfo> {
+    use super::stream::AsyncProcEventStream;
+
+    let mut stream = AsyncProcEventStream::with_pids(exec_kinds(), &[pid]).await?;
+
+    check_running(pid)?;
+
+    loop {
+        if let Some(r) = handle_event(pid, stream.recv().await?) {
+            return r;
+        }
+    }
+}
+
+#[inline]
+fn exec_kinds() -> ProcEventKinds {
+    ProcEventKinds::EXEC | ProcEventKinds::EXIT
+}
+
+// events are subscribed before, so an exec right after the check is not missed
+fn check_running(pid: Pid) -> Result<()> {
+    match procfs::is_running(pid) {
+        true => Ok(()),
+        false => Err(Error::from_raw_os_error(libc::ESRCH)),
+    }
+}
+
+fn handle_event(pid: Pid, event: ProcEvent) -> Option<Result<ExecInfo>> {
+    match event {
+        ProcEvent::Exec(e) if e.tgid == pid => Some(ExecInfo::capture(&e)),
+        ProcEvent::Exit(e) if e.tgid == pid && e.is_group_exit() => {
+            Some(Err(Error::from_raw_os_error(libc::ESRCH)))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,178 @@
+/// Waiters of the netlink backends and the filter generations they wait for
+use std::collections::HashMap;
+#[cfg(feature = "async-netlink")]
+use std::io::Error;
+
+use rustix::process::Pid;
+
+use super::{connection::PidUpdate, event::ExitEvent};
+use crate::{utils::procfs, ExitInfo};
+
+/// Waiters of each pid by registration id
+pub(super) type Registry<T> = HashMap<Pid, HashMap<u64, T>>;
+
+/// Sender of the exit of a whole thread group
+pub(super) trait NotifyExit {
+    fn notify(self, info: Option<ExitInfo>);
+}
+
+/// Sender of the exit of each thread
+pub(super) trait NotifyThreadExit {
+    fn notify(&self, event: ExitEvent);
+}
+
+#[derive(Debug)]
+pub(super) struct InterestGroup<E, T> {
+    pub exits: Registry<E>,
+    pub threads: Registry<T>,
+    next_id: u64,
+    /// Bumped whenever a pid is added or removed
+    pub generation: u64,
+    /// Generation passed by the filter
+    pub applied: u64,
+    /// Pids added or removed since the last update, passed to the filter one by one
+    changes: HashMap<Pid, bool>,
+    /// The next update passes every pid, after lost events or a failed update
+    pub full: bool,
+    /// The filter is being updated without the lock
+    pub applying: bool,
+    /// Generation the last failed update was meant to apply
+    #[cfg(feature = "async-netlink")]
+    pub failed: Option<(u64, Error)>,
+    /// The event loop has stopped, nothing will be notified anymore
+    pub closed: bool,
+}
+
+impl<E, T> Default for InterestGroup<E, T> {
+    fn default() -> Self {
+        Self {
+            exits: HashMap::new(),
+            threads: HashMap::new(),
+            next_id: 0,
+            generation: 0,
+            applied: 0,
+            changes: HashMap::new(),
+            full: false,
+            applying: false,
+            #[cfg(feature = "async-netlink")]
+            failed: None,
+            closed: false,
+        }
+    }
+}
+
+impl<E: NotifyExit, T: NotifyThreadExit> InterestGroup<E, T> {
+    pub fn contains(&self, pid: &Pid) -> bool {
+        self.exits.contains_key(pid) || self.threads.contains_key(pid)
+    }
+
+    pub fn keys(&self) -> Vec<Pid> {
+        let mut keys = (self.exits.keys().chain(self.threads.keys()))
+            .copied()
+            .collect::<Vec<_>>();
+        keys.sort_unstable_by_key(|x| x.as_raw_nonzero());
+        keys.dedup();
+        keys
+    }
+
+    pub fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    /// Add or remove waiters of `pid` with `f`, the generation is bumped if the pid set changed
+    pub fn update<R>(&mut self, pid: Pid, f: impl FnOnce(&mut Self) -> R) -> R {
+        let contained = self.contains(&pid);
+        let r = f(self);
+
+        if self.contains(&pid) != contained {
+            self.generation += 1;
+            self.changes.insert(pid, !contained);
+        }
+        r
+    }
+
+    /// Changes since the last update of the filter
+    pub fn take_update(&mut self) -> PidUpdate {
+        let changes = std::mem::take(&mut self.changes);
+
+        match std::mem::take(&mut self.full) {
+            true => PidUpdate::All(self.keys()),
+            false => PidUpdate::Changes(changes),
+        }
+    }
+
+    /// Notify waiters of `pid` and close the thread exit channels
+    pub fn complete(&mut self, pid: Pid, info: Option<ExitInfo>) {
+        self.update(pid, |x| {
+            x.threads.remove(&pid);
+            for notifier in x
+                .exits
+                .remove(&pid)
+                .into_iter()
+                .flat_map(|x| x.into_values())
+            {
+                notifier.notify(info);
+            }
+        });
+    }
+
+    /// Notify waiters of the thread, and of its process if it's the last one
+    pub fn dispatch(&mut self, exit: ExitEvent) {
+        if !self.contains(&exit.tgid) {
+            return;
+        }
+
+        let notifiers = self.threads.get(&exit.tgid);
+        for notifier in notifiers.into_iter().flat_map(HashMap::values) {
+            notifier.notify(exit);
+        }
+
+        // other threads of the group are still running
+        if exit.is_group_exit() {
+            self.complete(exit.tgid, Some(exit.info()));
+        }
+    }
+
+    /// Whether a waiter was registered with `id`
+    pub fn remove(&mut self, pid: Pid, id: u64) -> bool {
+        self.update(pid, |x| {
+            take(&mut x.exits, pid, id).is_some() || take(&mut x.threads, pid, id).is_some()
+        })
+    }
+
+    /// Remove the exit waiter registered with `id`, unless it was notified
+    pub fn take_exit(&mut self, pid: Pid, id: u64) -> Option<E> {
+        self.update(pid, |x| take(&mut x.exits, pid, id))
+    }
+
+    /// Events were lost, complete the waiters of processes that are gone meanwhile, the next
+    /// update passes every pid
+    pub fn resync(&mut self) {
+        for pid in self.keys() {
+            if !procfs::is_running(pid) {
+                self.complete(pid, None);
+            }
+        }
+        self.full = true;
+    }
+
+    /// Drop every waiter, they see a closed channel.
+    ///
+    /// The generations are kept, waiters of the filter would wait for an older one again.
+    pub fn close(&mut self) {
+        self.exits.clear();
+        self.threads.clear();
+        self.closed = true;
+    }
+}
+
+fn take<T>(registry: &mut Registry<T>, pid: Pid, id: u64) -> Option<T> {
+    let waiters = registry.get_mut(&pid)?;
+
+    let waiter = waiters.remove(&id);
+    if waiters.is_empty() {
+        registry.remove(&pid);
+    }
+    waiter
+}
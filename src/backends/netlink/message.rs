@@ -0,0 +1,272 @@
+/// Bounds checked decoding of received netlink messages
+use std::mem::offset_of;
+
+use super::{
+    binding::{
+        ack_proc_event, cb_id, cn_msg, nlmsghdr, proc_cn_event, proc_event, CN_IDX_PROC,
+        CN_VAL_PROC, NLMSGHDR_SIZE, NLMSG_ALIGNTO, NLMSG_DONE, NL_MESSAGE_BASE_SIZE,
+    },
+    event::ProcEvent,
+};
+
+/// Why a message doesn't carry a proc connector event
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum MessageError {
+    /// Shorter than its headers tell, or than the event it carries
+    Truncated,
+    /// Not sent by the proc connector
+    Foreign,
+    /// `what` of an event kind which isn't known, or isn't reported
+    Unknown(u32),
+    /// A pid the event is about is 0
+    Invalid,
+}
+
+/// Proc connector message, headers are checked against the bytes received
+#[derive(Debug, Clone, Copy)]
+pub(super) struct ConnectorMessage<'a> {
+    pub seq: u32,
+    pub ack: u32,
+    /// `cn_msg.len` bytes of data
+    pub data: &'a [u8],
+}
+
+/// `N` bytes at `offset`
+pub(super) fn read<const N: usize>(buf: &[u8], offset: usize) -> Result<[u8; N], MessageError> {
+    let bytes = buf.get(offset..).and_then(|x| x.get(..N));
+    bytes
+        .and_then(|x| x.try_into().ok())
+        .ok_or(MessageError::Truncated)
+}
+
+pub(super) fn read_u16(buf: &[u8], offset: usize) -> Result<u16, MessageError> {
+    read(buf, offset).map(u16::from_ne_bytes)
+}
+
+pub(super) fn read_u32(buf: &[u8], offset: usize) -> Result<u32, MessageError> {
+    read(buf, offset).map(u32::from_ne_bytes)
+}
+
+pub(super) fn read_u64(buf: &[u8], offset: usize) -> Result<u64, MessageError> {
+    read(buf, offset).map(u64::from_ne_bytes)
+}
+
+/// Every netlink message of a datagram, a malformed one is the last, so it's reported
+pub(super) fn netlink_messages(mut buf: &[u8]) -> impl Iterator<Item = &[u8]> {
+    std::iter::from_fn(move || {
+        if buf.is_empty() {
+            return None;
+        }
+
+        let len = read_u32(buf, offset_of!(nlmsghdr, nlmsg_len)).map_or(0, |x| x as usize);
+        if len < NLMSGHDR_SIZE || len > buf.len() {
+            return Some(std::mem::take(&mut buf));
+        }
+
+        let message = &buf[..len];
+        buf = buf
+            .get(len.next_multiple_of(NLMSG_ALIGNTO as usize)..)
+            .unwrap_or_default();
+
+        Some(message)
+    })
+}
+
+/// Check the headers of a single netlink message
+pub(super) fn parse_connector_message(buf: &[u8]) -> Result<ConnectorMessage<'_>, MessageError> {
+    let len = read_u32(buf, offset_of!(nlmsghdr, nlmsg_len))? as usize;
+    if len < NL_MESSAGE_BASE_SIZE {
+        // NLMSG_ERROR and the like are shorter
+        return Err(match buf.len() < len {
+            true => MessageError::Truncated,
+            false => MessageError::Foreign,
+        });
+    }
+    let buf = buf.get(..len).ok_or(MessageError::Truncated)?;
+
+    let nlmsg_type = read_u16(buf, offset_of!(nlmsghdr, nlmsg_type))?;
+    let cn_msg = |offset| NLMSGHDR_SIZE + offset;
+    let idx = read_u32(buf, cn_msg(offset_of!(cn_msg, id) + offset_of!(cb_id, idx)))?;
+    let val = read_u32(buf, cn_msg(offset_of!(cn_msg, id) + offset_of!(cb_id, val)))?;
+
+    if nlmsg_type != NLMSG_DONE as u16 || idx != CN_IDX_PROC || val != CN_VAL_PROC {
+        return Err(MessageError::Foreign);
+    }
+
+    let data_len = read_u16(buf, cn_msg(offset_of!(cn_msg, len)))? as usize;
+    let data = buf[NL_MESSAGE_BASE_SIZE..]
+        .get(..data_len)
+        .ok_or(MessageError::Truncated)?;
+
+    Ok(ConnectorMessage {
+        seq: read_u32(buf, cn_msg(offset_of!(cn_msg, seq)))?,
+        ack: read_u32(buf, cn_msg(offset_of!(cn_msg, ack)))?,
+        data,
+    })
+}
+
+/// `cn_msg.seq` and the event of a message
+pub(super) fn parse_event_message(buf: &[u8]) -> Result<(u32, ProcEvent), MessageError> {
+    let message = parse_connector_message(buf)?;
+    let event = ProcEvent::parse(message.data)?;
+
+    Ok((message.seq, event))
+}
+
+/// CPU and `cn_msg.seq` of any proc connector message, acknowledgements included
+pub(super) fn parse_sequence(buf: &[u8]) -> Result<(u32, u32), MessageError> {
+    let message = parse_connector_message(buf)?;
+    let cpu = read_u32(message.data, offset_of!(proc_event, cpu))?;
+
+    Ok((cpu, message.seq))
+}
+
+/// Error of an acknowledgement carrying `ack + 1`
+pub(super) fn parse_ack_message(buf: &[u8], ack: u32) -> Option<u32> {
+    let message = parse_connector_message(buf).ok()?;
+    let what = read_u32(message.data, offset_of!(proc_event, what)).ok()?;
+
+    if message.ack != ack.wrapping_add(1) || what != proc_cn_event::PROC_EVENT_NONE as u32 {
+        return None;
+    }
+
+    let err = offset_of!(proc_event, event_data) + offset_of!(ack_proc_event, err);
+    read_u32(message.data, err).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::netlink::binding::{exit_message, PROC_EVENT_SIZE};
+
+    fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+        buf[offset..offset + 2].copy_from_slice(&value.to_ne_bytes());
+    }
+
+    fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+        buf[offset..offset + 4].copy_from_slice(&value.to_ne_bytes());
+    }
+
+    fn exit_pid(buf: &[u8]) -> Result<(u32, i32), MessageError> {
+        match parse_event_message(buf)? {
+            (seq, ProcEvent::Exit(e)) => Ok((seq, e.pid.as_raw_nonzero().get())),
+            (_, e) => panic!("unexpected event {e:?}"),
+        }
+    }
+
+    #[test]
+    fn exit_event() {
+        assert_eq!(exit_pid(&exit_message(7, 42)), Ok((7, 42)));
+    }
+
+    #[test]
+    fn short_header() {
+        let buf = exit_message(0, 42);
+
+        assert_eq!(netlink_messages(&buf[..3]).collect::<Vec<_>>(), [&buf[..3]]);
+        assert_eq!(
+            parse_connector_message(&buf[..3]).err(),
+            Some(MessageError::Truncated)
+        );
+        // the whole netlink header, but not the connector one
+        let short = &buf[..NLMSGHDR_SIZE];
+        assert_eq!(netlink_messages(short).collect::<Vec<_>>(), [short]);
+        assert_eq!(
+            parse_connector_message(short).err(),
+            Some(MessageError::Truncated)
+        );
+    }
+
+    #[test]
+    fn length_above_buffer() {
+        let mut buf = exit_message(0, 42);
+        let len = buf.len() as u32 + 1;
+        write_u32(&mut buf, offset_of!(nlmsghdr, nlmsg_len), len);
+
+        assert_eq!(netlink_messages(&buf).collect::<Vec<_>>(), [&buf[..]]);
+        assert_eq!(exit_pid(&buf), Err(MessageError::Truncated));
+    }
+
+    #[test]
+    fn length_below_header() {
+        let mut buf = exit_message(0, 42);
+        write_u32(&mut buf, offset_of!(nlmsghdr, nlmsg_len), 4);
+
+        assert_eq!(netlink_messages(&buf).collect::<Vec<_>>(), [&buf[..]]);
+        assert_eq!(exit_pid(&buf), Err(MessageError::Foreign));
+    }
+
+    #[test]
+    fn data_past_payload() {
+        let mut buf = exit_message(0, 42);
+        let len = PROC_EVENT_SIZE as u16 + 1;
+        write_u16(&mut buf, NLMSGHDR_SIZE + offset_of!(cn_msg, len), len);
+
+        assert_eq!(exit_pid(&buf), Err(MessageError::Truncated));
+    }
+
+    #[test]
+    fn event_past_data() {
+        let mut buf = exit_message(0, 42);
+        write_u16(&mut buf, NLMSGHDR_SIZE + offset_of!(cn_msg, len), 8);
+
+        assert_eq!(exit_pid(&buf), Err(MessageError::Truncated));
+    }
+
+    #[test]
+    fn foreign() {
+        let mut buf = exit_message(0, 42);
+        let idx = NLMSGHDR_SIZE + offset_of!(cn_msg, id) + offset_of!(cb_id, idx);
+        write_u32(&mut buf, idx, CN_IDX_PROC + 1);
+
+        assert_eq!(exit_pid(&buf), Err(MessageError::Foreign));
+    }
+
+    #[test]
+    fn unknown_what() {
+        let mut buf = exit_message(0, 42);
+        write_u32(
+            &mut buf,
+            NL_MESSAGE_BASE_SIZE + offset_of!(proc_event, what),
+            0x400,
+        );
+
+        assert_eq!(exit_pid(&buf), Err(MessageError::Unknown(0x400)));
+    }
+
+    #[test]
+    fn zero_pid() {
+        assert_eq!(exit_pid(&exit_message(0, 0)), Err(MessageError::Invalid));
+    }
+
+    #[test]
+    fn multiple_messages() {
+        // the second one isn't aligned, the third one is padded
+        let mut first = exit_message(1, 10);
+        first.extend_from_slice(&[0xff; 3]);
+        let len = first.len() as u32;
+        write_u32(&mut first, offset_of!(nlmsghdr, nlmsg_len), len);
+        let data_len = PROC_EVENT_SIZE as u16 + 3;
+        write_u16(
+            &mut first,
+            NLMSGHDR_SIZE + offset_of!(cn_msg, len),
+            data_len,
+        );
+        assert_ne!(len % NLMSG_ALIGNTO, 0);
+        let second = exit_message(2, 20);
+
+        let mut buf = first.clone();
+        buf.resize(len.next_multiple_of(NLMSG_ALIGNTO) as usize, 0);
+        buf.extend_from_slice(&second);
+        buf.extend_from_slice(&exit_message(3, 30)[..8]);
+
+        let messages = netlink_messages(&buf).collect::<Vec<_>>();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0], &first[..]);
+        assert_eq!(messages[1], &second[..]);
+        assert_eq!(
+            messages.iter().map(|x| exit_pid(x)).collect::<Vec<_>>(),
+            [Ok((1, 10)), Ok((2, 20)), Err(MessageError::Truncated)]
+        );
+    }
+}
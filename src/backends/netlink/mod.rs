@@ -3,8 +3,27 @@ mod async_;
 mod binding;
 mod bpf;
 mod connection;
+#[cfg(feature = "ebpf")]
+mod ebpf;
+mod event;
+mod exec;
+mod interest;
+mod message;
+mod stream;
 mod sync;
+mod tree;
 
 #[cfg(feature = "async-netlink")]
-pub(crate) use async_::AsyncNetlinkBackend;
-pub(crate) use sync::NetlinkBackend;
+pub use async_::{AsyncExitReceiver, AsyncNetlinkBackend, AsyncThreadExitReceiver};
+pub use event::{
+    CommEvent, CoredumpEvent, ExecEvent, ExitEvent, ForkEvent, IdEvent, ProcEvent, ProcEventKinds,
+    PtraceEvent, SidEvent,
+};
+#[cfg(feature = "async-netlink")]
+pub use exec::wait_exec_async;
+pub use exec::{wait_exec, ExecInfo};
+#[cfg(feature = "async-netlink")]
+pub use stream::AsyncProcEventStream;
+pub use stream::ProcEventStream;
+pub use sync::{ExitReceiver, NetlinkBackend, ThreadExitReceiver};
+pub use tree::TreeWatcher;
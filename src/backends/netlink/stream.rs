@@ -0,0 +1,145 @@
+/// Proc connector event streams
+use std::{io::Result, time::Duration};
+
+use rustix::process::Pid;
+
+use super::{
+    connection::NetlinkConnection,
+    event::{ProcEvent, ProcEventKinds},
+};
+
+/// Blocking stream of proc connector events of every process on the system.
+///
+/// Requires `CAP_NET_ADMIN`.
+#[derive(Debug)]
+pub struct ProcEventStream {
+    netlink: NetlinkConnection,
+}
+
+impl ProcEventStream {
+    /// Subscribe to `kinds` of events, the others are dropped by the in-kernel filter.
+    pub fn new(kinds: ProcEventKinds) -> Result<Self> {
+        Self::subscribe(kinds, None)
+    }
+
+    /// Like [`new`](Self::new), but only pass events about the given TGIDs,
+    /// fork events are matched by the parent's TGID.
+    pub fn with_pids(kinds: ProcEventKinds, pids: &[Pid]) -> Result<Self> {
+        Self::subscribe(kinds, Some(pids))
+    }
+
+    fn subscribe(kinds: ProcEventKinds, pids: Option<&[Pid]>) -> Result<Self> {
+        let netlink = NetlinkConnection::new(kinds)?;
+        netlink.interest(pids)?;
+        netlink.start()?;
+
+        Ok(Self { netlink })
+    }
+
+    /// Wait for the next event, fails with [`ErrorKind::TimedOut`] when `timeout` elapsed.
+    ///
+    /// Fails with `ENOBUFS` when events were lost, the stream keeps working afterwards. Without
+    /// filters gaps in the kernel's sequence numbers are reported the same way.
+    ///
+    /// [`ErrorKind::TimedOut`]: std::io::ErrorKind::TimedOut
+    pub fn recv(&mut self, timeout: Option<Duration>) -> Result<ProcEvent> {
+        self.netlink.read_event(timeout, None)
+    }
+
+    /// Enlarge the socket receive buffer, beyond `net.core.rmem_max` with `CAP_NET_ADMIN`.
+    pub fn set_recv_buffer_size(&self, size: usize) -> Result<()> {
+        self.netlink.set_recv_buffer_size(size)
+    }
+
+    /// Number of times events were lost.
+    pub fn overflows(&self) -> u64 {
+        self.netlink.overflows()
+    }
+}
+
+impl Iterator for ProcEventStream {
+    type Item = Result<ProcEvent>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.recv(None))
+    }
+}
+
+#[cfg(feature = "async-netlink")]
+pub use self::async_stream::AsyncProcEventStream;
+
+#[cfg(feature = "async-netlink")]
+mod async_stream {
+    use std::{
+        future::poll_fn,
+        io::{Error, Result},
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use futures_core::Stream;
+    use rustix::process::Pid;
+
+    use super::{NetlinkConnection, ProcEvent, ProcEventKinds};
+    use crate::backends::netlink::connection::AsyncNetlinkConnection;
+
+    /// Async version of [`ProcEventStream`](super::ProcEventStream).
+    #[derive(Debug)]
+    pub struct AsyncProcEventStream {
+        netlink: AsyncNetlinkConnection,
+    }
+
+    impl AsyncProcEventStream {
+        /// Subscribe to `kinds` of events, must be called within a tokio runtime.
+        pub async fn new(kinds: ProcEventKinds) -> Result<Self> {
+            Self::subscribe(kinds, None).await
+        }
+
+        /// See [`ProcEventStream::with_pids`](super::ProcEventStream::with_pids).
+        pub async fn with_pids(kinds: ProcEventKinds, pids: &[Pid]) -> Result<Self> {
+            Self::subscribe(kinds, Some(pids.to_vec())).await
+        }
+
+        async fn subscribe(kinds: ProcEventKinds, pids: Option<Vec<Pid>>) -> Result<Self> {
+            // LISTEN waits for the acknowledgement of the kernel
+            let netlink = tokio::task::spawn_blocking(move || {
+                let netlink = NetlinkConnection::new(kinds)?;
+                netlink.interest(pids.as_deref())?;
+                netlink.start()?;
+                Ok::<_, Error>(netlink)
+            })
+            .await
+            .map_err(Error::other)??;
+
+            Ok(Self {
+                netlink: AsyncNetlinkConnection::new(netlink)?,
+            })
+        }
+
+        pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<ProcEvent>> {
+            self.netlink.poll_read_event(cx)
+        }
+
+        /// Wait for the next event, see [`ProcEventStream::recv`](super::ProcEventStream::recv).
+        pub async fn recv(&mut self) -> Result<ProcEvent> {
+            poll_fn(|cx| self.poll_recv(cx)).await
+        }
+
+        pub fn set_recv_buffer_size(&self, size: usize) -> Result<()> {
+            self.netlink.set_recv_buffer_size(size)
+        }
+
+        pub fn overflows(&self) -> u64 {
+            self.netlink.overflows()
+        }
+    }
+
+    impl Stream for AsyncProcEventStream {
+        type Item = Result<ProcEvent>;
+
+        fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.get_mut().poll_recv(cx).map(Some)
+        }
+    }
+}
@@ -1,10 +1,8 @@
 /// Sync netlink pid waiter
 use std::{
-    collections::HashMap,
     io::{Error, ErrorKind, Result},
-    iter,
-    sync::{Arc, Mutex},
-    thread,
+    sync::{Arc, Condvar, Mutex, MutexGuard, Weak},
+    thread::{self, JoinHandle},
     time::Duration,
 };
 
@@ -14,72 +12,332 @@ use rustix::{
     process::Pid,
 };
 
-use super::{binding::NL_CONNECTOR_MAX_MSG_SIZE, connection::NetlinkConnection};
-use crate::{backends::Backend, utils};
+use super::{
+    connection::NetlinkConnection,
+    event::{ExitEvent, ProcEvent, ProcEventKinds},
+    interest::{self, NotifyExit, NotifyThreadExit},
+};
+use crate::{
+    backends::Backend,
+    utils::{self, procfs},
+    ExitInfo,
+};
+
+/// Backend used by [`NetlinkBackend::shared`]
+static SHARED: Mutex<Weak<NetlinkBackend>> = Mutex::new(Weak::new());
+
+type ExitNotifier = crossbeam_channel::Sender<Option<ExitInfo>>;
+type ThreadExitNotifier = crossbeam_channel::Sender<ExitEvent>;
+
+type InterestGroup = interest::InterestGroup<ExitNotifier, ThreadExitNotifier>;
 
-type ExitNotifier = crossbeam_channel::Sender<()>;
-type ExitReceiver = crossbeam_channel::Receiver<()>;
+impl NotifyExit for ExitNotifier {
+    fn notify(self, info: Option<ExitInfo>) {
+        let _ = self.send(info); // don't care if the receiver is dropped
+    }
+}
+
+impl NotifyThreadExit for ThreadExitNotifier {
+    fn notify(&self, event: ExitEvent) {
+        let _ = self.send(event); // as above
+    }
+}
+
+/// Removes its waiter from the backend when dropped
+#[derive(Debug)]
+struct Registration {
+    inner: Weak<NetlinkBackendInner>,
+    pid: Pid,
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.upgrade() {
+            inner.unregister(self.pid, self.id);
+        }
+    }
+}
+
+/// Pending exit of a process, see [`NetlinkBackend::interest`].
+///
+/// Dropping it stops watching the process, unless someone else does.
+#[derive(Debug)]
+pub struct ExitReceiver {
+    rx: crossbeam_channel::Receiver<Option<ExitInfo>>,
+    registration: Registration,
+}
+
+impl ExitReceiver {
+    #[inline]
+    pub fn pid(&self) -> Pid {
+        self.registration.pid
+    }
+
+    /// Wait for the process to exit, `None` if its exit event was lost.
+    ///
+    /// Fails with [`ErrorKind::TimedOut`] when `timeout` elapsed, and with
+    /// [`ErrorKind::BrokenPipe`] if the backend stopped.
+    pub fn recv(&self, timeout: Option<Duration>) -> Result<Option<ExitInfo>> {
+        match timeout {
+            Some(timeout) => match self.rx.recv_timeout(timeout) {
+                Ok(info) => Ok(info),
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                    Err(ErrorKind::TimedOut.into())
+                }
+                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                    Err(ErrorKind::BrokenPipe.into())
+                }
+            },
+            None => self.rx.recv().map_err(|_| ErrorKind::BrokenPipe.into()),
+        }
+    }
+}
+
+/// Exits of the threads of a process, see [`NetlinkBackend::interest_threads`].
+///
+/// Dropping it stops watching the process, unless someone else does.
+#[derive(Debug)]
+pub struct ThreadExitReceiver {
+    rx: crossbeam_channel::Receiver<ExitEvent>,
+    registration: Registration,
+}
+
+impl ThreadExitReceiver {
+    #[inline]
+    pub fn pid(&self) -> Pid {
+        self.registration.pid
+    }
+
+    /// Wait for the next thread to exit, `None` once the whole thread group is gone.
+    ///
+    /// Fails with [`ErrorKind::TimedOut`] when `timeout` elapsed.
+    pub fn recv(&self, timeout: Option<Duration>) -> Result<Option<ExitEvent>> {
+        match timeout {
+            Some(timeout) => match self.rx.recv_timeout(timeout) {
+                Ok(event) => Ok(Some(event)),
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                    Err(ErrorKind::TimedOut.into())
+                }
+                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => Ok(None),
+            },
+            None => Ok(self.rx.recv().ok()),
+        }
+    }
+}
+
+impl Iterator for ThreadExitReceiver {
+    type Item = ExitEvent;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        self.rx.recv().ok()
+    }
+}
 
 #[derive(Debug)]
 struct NetlinkBackendInner {
     netlink: NetlinkConnection,
-    interest: Mutex<HashMap<Pid, Vec<ExitNotifier>>>,
+    interest: Mutex<InterestGroup>,
+    /// Notified once the filter was updated
+    applied: Condvar,
 }
 
 impl NetlinkBackendInner {
     fn new() -> Result<Arc<Self>> {
-        let netlink = NetlinkConnection::new()?;
+        let netlink = NetlinkConnection::new(ProcEventKinds::EXIT)?;
         netlink.interest(Some(&[]))?;
         netlink.start()?;
 
         Ok(Arc::new(Self {
             netlink,
             interest: Default::default(),
+            applied: Condvar::new(),
         }))
     }
 
-    fn interest(&self, pid: Pid) -> Result<ExitReceiver> {
+    fn interest(self: &Arc<Self>, pid: Pid) -> Result<ExitReceiver> {
+        let mut interest_group = self.interest.lock().unwrap();
+        let registration = self.register(&mut interest_group, pid)?;
+
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        interest_group.update(pid, |x| {
+            let waiters = x.exits.entry(pid).or_default();
+            waiters.insert(registration.id, tx);
+        });
+
+        if !self.watch(interest_group, pid)? {
+            // dropped from the filter by the next update
+            let mut interest_group = self.interest.lock().unwrap();
+            if let Some(tx) = interest_group.take_exit(pid, registration.id) {
+                let _ = tx.send(None);
+            }
+        }
+
+        Ok(ExitReceiver { rx, registration })
+    }
+
+    fn interest_threads(self: &Arc<Self>, pid: Pid) -> Result<ThreadExitReceiver> {
         let mut interest_group = self.interest.lock().unwrap();
+        let registration = self.register(&mut interest_group, pid)?;
 
-        let keys = interest_group
-            .keys()
-            .copied()
-            .chain(iter::once(pid))
-            .collect::<Vec<_>>();
+        // nothing to report if the process is gone, the channel is closed with the sender then
+        let (tx, rx) = crossbeam_channel::unbounded();
+        interest_group.update(pid, |x| {
+            let waiters = x.threads.entry(pid).or_default();
+            waiters.insert(registration.id, tx);
+        });
 
-        self.netlink.interest(Some(keys.as_slice()))?;
+        if !self.watch(interest_group, pid)? {
+            let mut interest_group = self.interest.lock().unwrap();
+            interest_group.remove(pid, registration.id);
+        }
 
-        let (tx, rx) = crossbeam_channel::bounded(0);
-        interest_group.entry(pid).or_default().push(tx);
-        Ok(rx)
+        Ok(ThreadExitReceiver { rx, registration })
+    }
+
+    fn register(
+        self: &Arc<Self>,
+        interest_group: &mut InterestGroup,
+        pid: Pid,
+    ) -> Result<Registration> {
+        if interest_group.closed {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+
+        Ok(Registration {
+            inner: Arc::downgrade(self),
+            pid,
+            id: interest_group.next_id(),
+        })
+    }
+
+    /// Wait until the filter passes events of `pid`, `false` if the process has exited
+    fn watch(&self, interest_group: MutexGuard<'_, InterestGroup>, pid: Pid) -> Result<bool> {
+        let generation = interest_group.generation;
+        drop(self.apply(interest_group, generation)?);
+
+        // the exit event was dropped if the process exited before the filter passed it
+        Ok(procfs::is_running(pid))
+    }
+
+    /// Update the filter to pass the pids of `generation` or a later one.
+    ///
+    /// A single update at a time is made without the lock, changes of everyone waiting for it
+    /// are applied together by the next one.
+    fn apply<'a>(
+        &'a self,
+        mut interest_group: MutexGuard<'a, InterestGroup>,
+        generation: u64,
+    ) -> Result<MutexGuard<'a, InterestGroup>> {
+        while interest_group.applied < generation {
+            if interest_group.closed {
+                return Err(ErrorKind::BrokenPipe.into());
+            }
+            if interest_group.applying {
+                interest_group = self
+                    .applied
+                    .wait(interest_group)
+                    .unwrap_or_else(|x| x.into_inner());
+                continue;
+            }
+
+            let target = interest_group.generation;
+            let update = interest_group.take_update();
+            interest_group.applying = true;
+            drop(interest_group);
+
+            let result = self.netlink.update(&update);
+
+            interest_group = self.interest.lock().unwrap_or_else(|x| x.into_inner());
+            interest_group.applying = false;
+            match result {
+                Ok(()) => interest_group.applied = target,
+                // the changes are lost
+                Err(_) => interest_group.full = true,
+            }
+            self.applied.notify_all();
+            result?;
+        }
+
+        Ok(interest_group)
+    }
+
+    fn unregister(&self, pid: Pid, id: u64) {
+        let mut interest_group = self.interest.lock().unwrap_or_else(|x| x.into_inner());
+
+        if interest_group.remove(pid, id) {
+            let generation = interest_group.generation;
+            let _ = self.apply(interest_group, generation).map(drop); // the event loop fails as well
+        }
     }
 
     fn handle_events(&self, timeout: Option<Duration>, aborter: BorrowedFd) -> Result<()> {
-        let mut buf = [0u8; NL_CONNECTOR_MAX_MSG_SIZE];
+        let mut lost = false;
 
         loop {
-            let pid = self.netlink.read_event(&mut buf, timeout, aborter)?;
+            // the kernel doesn't report another overflow before the queue is drained,
+            // so resync only after that
+            let timeout = if lost { Some(Duration::ZERO) } else { timeout };
+
+            let events = match self.netlink.read_events(timeout, Some(aborter)) {
+                Ok(events) => events,
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    lost = true;
+                    continue;
+                }
+                Err(e) if lost && e.kind() == ErrorKind::TimedOut => {
+                    lost = false;
+                    match self.resync() {
+                        Ok(()) => continue,
+                        Err(_) => return Ok(()), // OwnedFd is dropped
+                    }
+                }
+                Err(e) => return Err(e),
+            };
 
             let mut interest_group = self.interest.lock().unwrap();
-            if let Some(notifiers) = interest_group.remove(&pid) {
-                for notifier in notifiers {
-                    let _ = notifier.send(()); // don't care if the receiver is dropped
+
+            for event in events {
+                if let ProcEvent::Exit(exit) = event {
+                    interest_group.dispatch(exit);
                 }
             }
 
-            let keys = interest_group.keys().copied().collect::<Vec<_>>();
-            match self.netlink.interest(Some(&keys)) {
-                Ok(()) => (),
+            // the filter is updated once for all exits of the batch, if any
+            let generation = interest_group.generation;
+            match self.apply(interest_group, generation) {
+                Ok(_) => (),
                 Err(_) => return Ok(()), // OwnedFd is dropped
             }
         }
     }
+
+    /// Events were lost, complete the waiters of processes that are gone meanwhile
+    fn resync(&self) -> Result<()> {
+        let mut interest_group = self.interest.lock().unwrap();
+        interest_group.resync();
+
+        let generation = interest_group.generation;
+        self.apply(interest_group, generation).map(drop)
+    }
+
+    /// Called once the event loop stopped, waiters are woken with [`ErrorKind::BrokenPipe`]
+    fn close(&self) {
+        let mut interest_group = self.interest.lock().unwrap_or_else(|x| x.into_inner());
+        interest_group.close();
+        self.applied.notify_all();
+    }
 }
 
+/// Waits for processes to exit through the proc connector.
+///
+/// Events are received by a background thread, which lives as long as the backend.
 #[derive(Debug)]
 pub struct NetlinkBackend {
     inner: Arc<NetlinkBackendInner>,
     aborter: OwnedFd,
+    thread: Option<JoinHandle<()>>,
 }
 
 impl NetlinkBackend {
@@ -87,53 +345,93 @@ impl NetlinkBackend {
         let inner = NetlinkBackendInner::new()?;
         let (rx, tx) = pipe::pipe_with(PipeFlags::DIRECT | PipeFlags::CLOEXEC)?;
 
-        thread::spawn({
+        let thread = thread::spawn({
             let inner = inner.clone();
             move || {
-                match inner.handle_events(None, rx.as_fd()) {
-                    Ok(()) => { /* connection closed */ }
-                    Err(e) if e.kind() == ErrorKind::ConnectionAborted => {
-                        /* connection closed */
-                    }
-                    Err(e) => panic!("{e:?}"),
-                }
+                // the result doesn't matter, waiters see a closed channel either way
+                let _ = inner.handle_events(None, rx.as_fd());
+                inner.close();
             }
         });
 
-        Ok(Self { inner, aborter: tx })
+        Ok(Self {
+            inner,
+            aborter: tx,
+            thread: Some(thread),
+        })
     }
 
+    /// Backend shared by every caller in the process, used by [`waitpid`](crate::waitpid).
+    ///
+    /// Created on first use, the socket and thread are torn down once the last [`Arc`] is
+    /// dropped.
+    pub fn shared() -> Result<Arc<Self>> {
+        let mut shared = SHARED.lock().unwrap_or_else(|x| x.into_inner());
+
+        if let Some(backend) = shared.upgrade() {
+            return Ok(backend);
+        }
+
+        let backend = Arc::new(Self::new()?);
+        *shared = Arc::downgrade(&backend);
+        Ok(backend)
+    }
+
+    /// Enlarge the socket receive buffer, so that bursts of exits don't overflow it.
+    ///
+    /// Goes beyond `net.core.rmem_max` with `CAP_NET_ADMIN`.
+    pub fn set_recv_buffer_size(&self, size: usize) -> Result<()> {
+        self.inner.netlink.set_recv_buffer_size(size)
+    }
+
+    /// Number of times the socket overflowed and exit events were lost.
+    ///
+    /// Waiters of processes that exited meanwhile receive `None`.
+    pub fn overflows(&self) -> u64 {
+        self.inner.netlink.overflows()
+    }
+
+    /// Receive the [`ExitInfo`] once the whole thread group of `pid` is gone.
+    ///
+    /// Receives `None` right away if the process has already exited.
+    ///
+    /// Stops watching the process once every receiver of it is dropped.
     pub fn interest(&self, pid: Pid) -> Result<ExitReceiver> {
         self.inner.interest(pid)
     }
+
+    /// Receive the [`ExitEvent`] of every thread of `pid`.
+    ///
+    /// The channel is closed after the exit of the last thread, or right away if the process
+    /// has already exited.
+    pub fn interest_threads(&self, pid: Pid) -> Result<ThreadExitReceiver> {
+        self.inner.interest_threads(pid)
+    }
+
+    /// Wait for the process to exit, `None` if its exit event was lost.
+    pub fn waitpid(&self, pid: Pid, timeout: Option<Duration>) -> Result<Option<ExitInfo>> {
+        if !utils::process_exists(pid) {
+            return Err(Error::from_raw_os_error(libc::ESRCH));
+        }
+
+        self.interest(pid)?.recv(timeout)
+    }
 }
 
 impl Drop for NetlinkBackend {
     fn drop(&mut self) {
         let _ = self.inner.netlink.stop();
         let _ = rustix::io::write(self.aborter.as_fd(), &[0u8]);
+
+        // the socket is closed with the thread
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
     }
 }
 
 impl Backend for NetlinkBackend {
-    fn waitpid(&self, pid: Pid, timeout: Option<Duration>) -> Result<()> {
-        if !utils::process_exists(pid) {
-            return Err(Error::from_raw_os_error(libc::ESRCH));
-        }
-
-        let rx = self.interest(pid)?;
-
-        match timeout {
-            Some(timeout) => match rx.recv_timeout(timeout) {
-                Ok(()) => Ok(()),
-                Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
-                    Err(ErrorKind::TimedOut.into())
-                }
-                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
-                    Err(ErrorKind::BrokenPipe.into())
-                }
-            },
-            None => rx.recv().map_err(|_| ErrorKind::BrokenPipe.into()),
-        }
+    fn waitpid(&self, pid: Pid, timeout: Option<Duration>) -> Result<Option<ExitInfo>> {
+        NetlinkBackend::waitpid(self, pid, timeout)
     }
 }
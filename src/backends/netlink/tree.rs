@@ -0,0 +1,165 @@
+/// Process tree waiter
+use std::{
+    collections::HashSet,
+    io::{Error, ErrorKind, Result},
+    time::{Duration, Instant},
+};
+
+use rustix::process::Pid;
+
+use super::{
+    connection::NetlinkConnection,
+    event::{ProcEvent, ProcEventKinds},
+};
+use crate::{utils::procfs, ExitInfo};
+
+/// Waiter for a process and all of its descendants.
+///
+/// The tree is seeded from `/proc`, then grows with every fork of its members, so descendants
+/// keep being tracked after they are reparented. Processes reparented away before the watcher
+/// was created can't be found.
+///
+/// Requires `CAP_NET_ADMIN`.
+#[derive(Debug)]
+pub struct TreeWatcher {
+    netlink: NetlinkConnection,
+    members: HashSet<Pid>,
+    /// Events were lost, rescan once the socket is drained
+    lost: bool,
+}
+
+impl TreeWatcher {
+    pub fn new(root: Pid) -> Result<Self> {
+        let netlink = NetlinkConnection::new(ProcEventKinds::FORK | ProcEventKinds::EXIT)?;
+        netlink.interest(Some(&[root]))?;
+        netlink.start()?;
+
+        if !procfs::is_running(root) {
+            return Err(Error::from_raw_os_error(libc::ESRCH));
+        }
+
+        let mut watcher = Self {
+            netlink,
+            members: procfs::process_tree(root)?,
+            lost: false,
+        };
+        watcher.update_interest()?;
+
+        // forks and exits between the scan and the filter update were dropped by the old filter
+        watcher.rescan()?;
+
+        Ok(watcher)
+    }
+
+    /// Processes of the tree which are still running.
+    pub fn members(&self) -> impl Iterator<Item = Pid> + '_ {
+        self.members.iter().copied()
+    }
+
+    /// Whether every member of the tree has exited.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Wait for the next member to exit, `None` if the whole tree has exited.
+    ///
+    /// When the socket overflows the tree is scanned again, exits lost with it are not reported.
+    pub fn next_exit(&mut self, timeout: Option<Duration>) -> Result<Option<ExitInfo>> {
+        let deadline = timeout.map(|x| Instant::now() + x);
+
+        while !self.members.is_empty() {
+            let timeout = match self.lost {
+                // the kernel doesn't report another overflow before the queue is drained
+                true => Some(Duration::ZERO),
+                false => deadline.map(|x| x.saturating_duration_since(Instant::now())),
+            };
+
+            let event = match self.netlink.read_event(timeout, None) {
+                Ok(event) => event,
+                Err(e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    self.lost = true;
+                    continue;
+                }
+                Err(e) if self.lost && e.kind() == ErrorKind::TimedOut => {
+                    self.lost = false;
+                    self.rescan()?;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let parent_is_member = |x: Option<Pid>| x.is_some_and(|x| self.members.contains(&x));
+            match event {
+                ProcEvent::Fork(e) if !e.is_thread() && parent_is_member(e.parent_tgid) => {
+                    self.adopt(e.child_tgid)?;
+                }
+                ProcEvent::Exit(e) if self.members.contains(&e.tgid) && e.is_group_exit() => {
+                    self.members.remove(&e.tgid);
+                    self.update_interest()?;
+                    return Ok(Some(e.info()));
+                }
+                _ => (),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Wait for the whole tree to exit.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<()> {
+        let deadline = timeout.map(|x| Instant::now() + x);
+
+        loop {
+            let timeout = deadline.map(|x| x.saturating_duration_since(Instant::now()));
+
+            if self.next_exit(timeout)?.is_none() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Add a forked member, the old filter dropped whatever it did before the update
+    fn adopt(&mut self, child: Pid) -> Result<()> {
+        self.members.insert(child);
+        self.update_interest()?;
+
+        let mut changed = false;
+        for pid in procfs::process_tree(child)? {
+            let running = procfs::is_running(pid);
+            changed |= match pid == child {
+                true => !running && self.members.remove(&pid),
+                false => running && self.members.insert(pid),
+            };
+        }
+
+        match changed {
+            true => self.update_interest(),
+            false => Ok(()),
+        }
+    }
+
+    /// Add descendants of members and drop exited ones, then update the filter
+    fn rescan(&mut self) -> Result<()> {
+        let members = self.members.iter().copied().collect::<Vec<_>>();
+        for pid in members {
+            // only fails if procfs is unavailable
+            self.members.extend(procfs::process_tree(pid)?);
+        }
+        self.members.retain(|&pid| procfs::is_running(pid));
+        self.update_interest()
+    }
+
+    fn update_interest(&self) -> Result<()> {
+        let pids = self.members.iter().copied().collect::<Vec<_>>();
+        self.netlink.interest(Some(&pids))
+    }
+}
+
+impl Iterator for TreeWatcher {
+    type Item = Result<ExitInfo>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_exit(None).transpose()
+    }
+}
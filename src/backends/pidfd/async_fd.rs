@@ -1,24 +1,44 @@
 use std::{
     future::Future,
-    io::Result,
-    os::fd::OwnedFd,
+    io::{Error, ErrorKind, Result},
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd},
+    path::PathBuf,
     pin::Pin,
-    task::{Context, Poll},
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
 };
 
-use rustix::process::{pidfd_open, Pid, PidfdFlags};
-use tokio::io::{unix::AsyncFd, Interest};
+use rustix::process::{pidfd_open, Pid, PidfdFlags, Signal, WaitidOptions};
+use tokio::io::{
+    unix::{AsyncFd, AsyncFdRegisterError},
+    Interest, Ready,
+};
+
+use super::Termination;
+use crate::{ExitInfo, WaitStatus};
 
 #[derive(Debug)]
-struct PidFdInner(AsyncFd<OwnedFd>);
+struct PidFdInner {
+    fd: AsyncFd<OwnedFd>,
+    pid: Pid,
+}
 
 impl PidFdInner {
     fn new(pid: Pid) -> Result<Self> {
-        AsyncFd::with_interest(pidfd_open(pid, PidfdFlags::empty())?, Interest::READABLE).map(Self)
+        Self::from_parts(pidfd_open(pid, PidfdFlags::empty())?, pid).map_err(|(_, e)| e)
+    }
+
+    /// `fd` is handed back if it can't be registered
+    fn from_parts(fd: OwnedFd, pid: Pid) -> std::result::Result<Self, (OwnedFd, Error)> {
+        // SAFETY: the OwnedFd is moved into AsyncFd and stays open as long as it
+        let fd = unsafe { AsyncFd::register_with_interest(fd, Interest::READABLE) }
+            .map_err(AsyncFdRegisterError::into_parts)?;
+
+        Ok(Self { fd, pid })
     }
 
     fn poll_exit(&self, cx: &mut Context<'_>) -> Poll<Result<()>> {
-        self.0.poll_read_ready(cx).map_ok(|_| ())
+        self.fd.poll_read_ready(cx).map_ok(|_| ())
     }
 }
 
@@ -51,6 +71,71 @@ impl Future for AsyncPidFdExited<'_> {
     }
 }
 
+pub struct AsyncPidFdExitInfo<'a> {
+    pidfd: &'a PidFdInner,
+    /// Duplicate of the pidfd registered on its own, its readiness of the exit is cleared to
+    /// wait for the reap, which must not hide the exit from the other futures
+    reaped: Option<AsyncFd<OwnedFd>>,
+}
+
+impl Future for AsyncPidFdExitInfo<'_> {
+    type Output = Result<ExitInfo>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        let reaped = match &mut this.reaped {
+            Some(reaped) => reaped,
+            None => {
+                let fd = this.pidfd.fd.get_ref().try_clone()?;
+                // SAFETY: the OwnedFd is moved into AsyncFd and stays open as long as it
+                let fd = unsafe { AsyncFd::register_with_interest(fd, Interest::READABLE) }?;
+                this.reaped.insert(fd)
+            }
+        };
+
+        // the exit status is recorded once the process is reaped, which is reported as POLLHUP
+        loop {
+            let mut guard = ready!(reaped.poll_read_ready(cx))?;
+
+            if guard.ready().is_read_closed() {
+                break;
+            }
+            guard.clear_ready_matching(Ready::READABLE);
+        }
+
+        Poll::Ready(super::exit_info(
+            this.pidfd.fd.get_ref().as_fd(),
+            this.pidfd.pid,
+        ))
+    }
+}
+
+pub struct AsyncPidFdWaitStatus<'a> {
+    pidfd: &'a PidFdInner,
+    /// The process is a child of the caller, checked before waiting for it
+    checked: bool,
+}
+
+impl Future for AsyncPidFdWaitStatus<'_> {
+    type Output = Result<WaitStatus>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let fd = this.pidfd.fd.get_ref().as_fd();
+
+        if !this.checked {
+            super::check_child(fd)?;
+            this.checked = true;
+        }
+
+        ready!(this.pidfd.poll_exit(cx))?;
+
+        let status = super::wait_status(fd, WaitidOptions::EXITED);
+        Poll::Ready(status.and_then(|x| x.ok_or_else(|| ErrorKind::WouldBlock.into())))
+    }
+}
+
 #[derive(Debug)]
 pub struct AsyncPidFd(PidFdInner);
 
@@ -60,15 +145,115 @@ impl AsyncPidFd {
         PidFdInner::new(pid).map(Self)
     }
 
+    /// `fd` must be a pidfd of `pid`, must be called within a tokio runtime
+    pub(super) fn from_parts(fd: OwnedFd, pid: Pid) -> Result<Self> {
+        PidFdInner::from_parts(fd, pid)
+            .map(Self)
+            .map_err(|(_, e)| e)
+    }
+
     #[inline]
-    pub fn wait(&self) -> AsyncPidFdWait {
+    pub fn wait(&self) -> AsyncPidFdWait<'_> {
         AsyncPidFdWait { pidfd: &self.0 }
     }
 
     #[inline]
-    pub fn is_exited(&self) -> AsyncPidFdExited {
+    pub fn is_exited(&self) -> AsyncPidFdExited<'_> {
         AsyncPidFdExited { pidfd: &self.0 }
     }
+
+    /// See [`PidFd::send_signal`].
+    ///
+    /// [`PidFd::send_signal`]: super::PidFd::send_signal
+    #[inline]
+    pub fn send_signal(&self, signal: Signal, info: Option<&libc::siginfo_t>) -> Result<()> {
+        super::send_signal(self.0.fd.get_ref().as_fd(), signal, info)
+    }
+
+    /// See [`PidFd::terminate`], the runtime must have the time driver enabled.
+    ///
+    /// [`PidFd::terminate`]: super::PidFd::terminate
+    pub async fn terminate(&self, policy: &[(Signal, Duration)]) -> Result<Termination> {
+        let start = Instant::now();
+        let mut step = None;
+
+        for (i, &(signal, grace)) in policy.iter().enumerate() {
+            match self.send_signal(signal, None) {
+                Ok(()) => step = Some(i),
+                // exited during the previous grace period
+                Err(e) if e.kind() == ErrorKind::NotFound => break,
+                Err(e) => return Err(e),
+            }
+
+            match tokio::time::timeout(grace, self.wait()).await {
+                Ok(Ok(())) => break,
+                Ok(Err(e)) => return Err(e),
+                Err(_elapsed) => continue,
+            }
+        }
+
+        match self.is_exited().await? {
+            true => Ok(Termination::new(policy, step, start)),
+            false => Err(ErrorKind::TimedOut.into()),
+        }
+    }
+
+    /// See [`PidFd::get_fd`].
+    ///
+    /// [`PidFd::get_fd`]: super::PidFd::get_fd
+    #[inline]
+    pub fn get_fd(&self, target: RawFd) -> Result<OwnedFd> {
+        super::get_fd(self.0.fd.get_ref().as_fd(), target)
+    }
+
+    /// See [`PidFd::fds`].
+    ///
+    /// [`PidFd::fds`]: super::PidFd::fds
+    #[inline]
+    pub fn fds(&self) -> Result<Vec<(RawFd, PathBuf)>> {
+        super::fds(self.0.fd.get_ref().as_fd(), self.0.pid)
+    }
+
+    /// See [`PidFd::exit_info`].
+    ///
+    /// [`PidFd::exit_info`]: super::PidFd::exit_info
+    #[inline]
+    pub fn exit_info(&self) -> Result<ExitInfo> {
+        super::exit_info(self.0.fd.get_ref().as_fd(), self.0.pid)
+    }
+
+    /// Wait for the process to be reaped and query its exit status, see
+    /// [`PidFd::wait_exit_info`].
+    ///
+    /// [`PidFd::wait_exit_info`]: super::PidFd::wait_exit_info
+    #[inline]
+    pub fn wait_exit_info(&self) -> AsyncPidFdExitInfo<'_> {
+        AsyncPidFdExitInfo {
+            pidfd: &self.0,
+            reaped: None,
+        }
+    }
+
+    /// See [`PidFd::try_wait`].
+    ///
+    /// [`PidFd::try_wait`]: super::PidFd::try_wait
+    #[inline]
+    pub fn try_wait(&self) -> Result<Option<WaitStatus>> {
+        let options = WaitidOptions::EXITED | WaitidOptions::STOPPED | WaitidOptions::CONTINUED;
+
+        super::wait_status(self.0.fd.get_ref().as_fd(), options)
+    }
+
+    /// Wait for a child process to exit and reap it, see [`PidFd::wait_status`].
+    ///
+    /// [`PidFd::wait_status`]: super::PidFd::wait_status
+    #[inline]
+    pub fn wait_status(&self) -> AsyncPidFdWaitStatus<'_> {
+        AsyncPidFdWaitStatus {
+            pidfd: &self.0,
+            checked: false,
+        }
+    }
 }
 
 impl Future for AsyncPidFd {
@@ -78,3 +263,63 @@ impl Future for AsyncPidFd {
         self.0.poll_exit(cx)
     }
 }
+
+impl AsFd for AsyncPidFd {
+    #[inline]
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.fd.get_ref().as_fd()
+    }
+}
+
+impl AsRawFd for AsyncPidFd {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.fd.as_raw_fd()
+    }
+}
+
+/// Fails with [`ErrorKind::InvalidInput`] if `fd` is not a pidfd, `fd` is handed back along
+/// with the error, must be called within a tokio runtime.
+impl TryFrom<OwnedFd> for AsyncPidFd {
+    type Error = (OwnedFd, Error);
+
+    fn try_from(fd: OwnedFd) -> std::result::Result<Self, Self::Error> {
+        let pid = match super::pidfd_pid(fd.as_fd()) {
+            Ok(pid) => pid,
+            Err(e) => return Err((fd, e)),
+        };
+
+        PidFdInner::from_parts(fd, pid).map(Self)
+    }
+}
+
+impl From<AsyncPidFd> for OwnedFd {
+    #[inline]
+    fn from(pidfd: AsyncPidFd) -> Self {
+        pidfd.0.fd.into_inner()
+    }
+}
+
+/// Fails with [`ErrorKind::NotFound`] or `ECHILD` if the child has already been reaped, must
+/// be called within a tokio runtime.
+impl TryFrom<&std::process::Child> for AsyncPidFd {
+    type Error = Error;
+
+    fn try_from(child: &std::process::Child) -> Result<Self> {
+        let (fd, pid) = super::open_child(child.id())?;
+
+        Self::from_parts(fd, pid)
+    }
+}
+
+/// Fails with [`ErrorKind::NotFound`] or `ECHILD` if the child has already been reaped, must
+/// be called within a tokio runtime.
+impl TryFrom<&tokio::process::Child> for AsyncPidFd {
+    type Error = Error;
+
+    fn try_from(child: &tokio::process::Child) -> Result<Self> {
+        let (fd, pid) = super::open_child(child.id().ok_or_else(super::exited)?)?;
+
+        Self::from_parts(fd, pid)
+    }
+}
@@ -0,0 +1,34 @@
+use std::mem::size_of;
+
+// pidfd.h
+pub(super) const PIDFS_IOCTL_MAGIC: u32 = 0xFF;
+
+pub(super) const PIDFD_INFO_PID: u64 = 1 << 0;
+pub(super) const PIDFD_INFO_EXIT: u64 = 1 << 3;
+
+// _IOWR(PIDFS_IOCTL_MAGIC, 11, struct pidfd_info)
+pub(super) const PIDFD_GET_INFO: u32 = (3 << 30) // _IOC_READ | _IOC_WRITE
+    | ((size_of::<pidfd_info>() as u32) << 16)
+    | (PIDFS_IOCTL_MAGIC << 8)
+    | 11;
+
+/// `PIDFD_INFO_SIZE_VER0` layout, the only size Linux 6.13 accepts
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+pub(super) struct pidfd_info {
+    pub mask: u64,
+    pub cgroupid: u64,
+    pub pid: u32,
+    pub tgid: u32,
+    pub ppid: u32,
+    pub ruid: u32,
+    pub rgid: u32,
+    pub euid: u32,
+    pub egid: u32,
+    pub suid: u32,
+    pub sgid: u32,
+    pub fsuid: u32,
+    pub fsgid: u32,
+    pub exit_code: i32,
+}
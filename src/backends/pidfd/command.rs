@@ -0,0 +1,57 @@
+use std::{
+    io::{ErrorKind, Result},
+    os::fd::OwnedFd,
+    process::{Child, Command},
+};
+
+use rustix::process::Pid;
+
+#[cfg(feature = "async")]
+use super::AsyncPidFd;
+use super::PidFd;
+
+/// Spawn a [`Command`] along with a pidfd which is guaranteed to refer to the child.
+pub trait CommandExt {
+    /// Like [`Command::spawn`], the child must be waited through the pidfd or the [`Child`].
+    ///
+    /// std can't spawn with `clone3(CLONE_PIDFD)`, so the pidfd is opened right after, the pid
+    /// can't be reused meanwhile since the child is only reaped through the [`Child`]. Fails
+    /// with `ECHILD` or [`ErrorKind::NotFound`] if something else reaped it, like `waitpid(-1)`
+    /// or an ignored `SIGCHLD`. The child is killed and reaped if the pidfd can't be opened
+    /// otherwise.
+    fn spawn_pidfd(&mut self) -> Result<(Child, PidFd)>;
+
+    /// Async version of [`spawn_pidfd`](Self::spawn_pidfd), must be called within a tokio
+    /// runtime.
+    #[cfg(feature = "async")]
+    fn spawn_async_pidfd(&mut self) -> Result<(Child, AsyncPidFd)>;
+}
+
+impl CommandExt for Command {
+    fn spawn_pidfd(&mut self) -> Result<(Child, PidFd)> {
+        spawn(self, PidFd::from_parts)
+    }
+
+    #[cfg(feature = "async")]
+    fn spawn_async_pidfd(&mut self) -> Result<(Child, AsyncPidFd)> {
+        spawn(self, AsyncPidFd::from_parts)
+    }
+}
+
+fn spawn<T>(command: &mut Command, pidfd: fn(OwnedFd, Pid) -> Result<T>) -> Result<(Child, T)> {
+    let mut child = command.spawn()?;
+
+    match super::open_child(child.id()).and_then(|(fd, pid)| pidfd(fd, pid)) {
+        Ok(pidfd) => Ok((child, pidfd)),
+        // reaped by something else, the pid may refer to another process already
+        Err(e) if e.kind() == ErrorKind::NotFound || e.raw_os_error() == Some(libc::ECHILD) => {
+            Err(e)
+        }
+        Err(e) => {
+            // still unreaped, nobody would wait for it otherwise
+            let _ = child.kill();
+            let _ = child.wait();
+            Err(e)
+        }
+    }
+}
@@ -1,27 +1,289 @@
 #[cfg(feature = "async")]
 mod async_fd;
+mod binding;
+mod command;
 mod sync_fd;
 
 use std::{
+    fs,
     io::{Error, ErrorKind, Result},
-    time::Duration,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd},
+    path::PathBuf,
+    ptr,
+    time::{Duration, Instant},
 };
 
 use rustix::{
     event::{poll, PollFd, PollFlags},
-    process::{pidfd_open, Pid, PidfdFlags},
+    process::{pidfd_open, waitid, Pid, PidfdFlags, Signal, WaitId, WaitidOptions},
 };
 
 #[cfg(feature = "async")]
-pub use self::async_fd::{AsyncPidFd, AsyncPidFdExited, AsyncPidFdWait};
-pub use self::sync_fd::PidFd;
+pub use self::async_fd::{
+    AsyncPidFd, AsyncPidFdExitInfo, AsyncPidFdExited, AsyncPidFdWait, AsyncPidFdWaitStatus,
+};
+use self::binding::*;
+pub use self::{command::CommandExt, sync_fd::PidFd};
 use super::Backend;
+use crate::{ExitInfo, WaitStatus};
+
+/// Outcome of [`PidFd::terminate`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Termination {
+    /// Index of the policy step whose signal was sent last, `None` if the process had exited
+    /// before the first one
+    pub step: Option<usize>,
+    /// Signal of that step
+    pub signal: Option<Signal>,
+    /// Since the first signal was sent
+    pub elapsed: Duration,
+}
+
+impl Termination {
+    fn new(policy: &[(Signal, Duration)], step: Option<usize>, start: Instant) -> Self {
+        Self {
+            step,
+            signal: step.map(|x| policy[x].0),
+            elapsed: start.elapsed(),
+        }
+    }
+}
+
+/// Query exit status of an exited process through `PIDFD_GET_INFO`, Linux 6.15+ only.
+///
+/// Returns [`ErrorKind::WouldBlock`] if the process is still running or has not been reaped yet,
+/// the kernel records the exit status when it's reaped.
+fn exit_info(fd: BorrowedFd, pid: Pid) -> Result<ExitInfo> {
+    let mut info = pidfd_info {
+        mask: PIDFD_INFO_EXIT,
+        ..Default::default()
+    };
+
+    // SAFETY: info is a valid pidfd_info, its size is encoded in the ioctl request
+    let ret = unsafe { libc::ioctl(fd.as_raw_fd(), PIDFD_GET_INFO as _, &mut info) };
+
+    if ret == -1 {
+        let e = Error::last_os_error();
+        return match e.raw_os_error() {
+            // kernel 6.12- knows nothing about PIDFD_GET_INFO,
+            // 6.13 and 6.14 can't report anything of a reaped process
+            Some(libc::ENOTTY | libc::EINVAL | libc::ESRCH) => Err(Error::new(
+                ErrorKind::Unsupported,
+                "PIDFD_GET_INFO exit information requires Linux 6.15+",
+            )),
+            _ => Err(e),
+        };
+    }
+
+    if info.mask & PIDFD_INFO_EXIT == 0 {
+        return Err(ErrorKind::WouldBlock.into());
+    }
+
+    Ok(ExitInfo {
+        pid,
+        status: info.exit_code,
+        exit_signal: None,
+        // only available while the zombie is still around
+        parent_pid: match info.mask & PIDFD_INFO_PID {
+            0 => None,
+            _ => Pid::from_raw(info.ppid as i32),
+        },
+        timestamp_ns: None,
+    })
+}
+
+/// Send a signal through `pidfd_send_signal`, it can't reach another process reusing the pid.
+///
+/// Fails with [`ErrorKind::NotFound`] if the process has already exited, including zombies,
+/// which would silently ignore the signal.
+fn send_signal(fd: BorrowedFd, signal: Signal, info: Option<&libc::siginfo_t>) -> Result<()> {
+    // the pidfd is readable once the process has exited
+    let mut fds = [PollFd::new(&fd, PollFlags::IN)];
+    if poll(&mut fds, 0)? != 0 {
+        return Err(exited());
+    }
+
+    let info = info.map_or(ptr::null(), |x| x as *const libc::siginfo_t);
+
+    // SAFETY: info is null or points to a live siginfo_t, no flags are defined
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_pidfd_send_signal,
+            fd.as_raw_fd(),
+            signal as libc::c_int,
+            info,
+            0,
+        )
+    };
+
+    match ret {
+        -1 => match Error::last_os_error() {
+            e if e.raw_os_error() == Some(libc::ESRCH) => Err(exited()),
+            e => Err(e),
+        },
+        _ => Ok(()),
+    }
+}
+
+fn exited() -> Error {
+    Error::new(ErrorKind::NotFound, "the process has already exited")
+}
+
+/// Duplicate `target` of the process into the caller through `pidfd_getfd`, close-on-exec.
+///
+/// Fails with [`ErrorKind::PermissionDenied`] without ptrace access to the process, with
+/// [`ErrorKind::InvalidInput`] if `target` isn't open in it, and with [`ErrorKind::NotFound`]
+/// if it has already exited.
+fn get_fd(fd: BorrowedFd, target: RawFd) -> Result<OwnedFd> {
+    // SAFETY: plain integer arguments, no flags are defined
+    let ret = unsafe { libc::syscall(libc::SYS_pidfd_getfd, fd.as_raw_fd(), target, 0) };
+
+    if ret == -1 {
+        let e = Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::EPERM) => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "ptrace access to the process is denied",
+            )),
+            Some(libc::EBADF) => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "the file descriptor isn't open in the process",
+            )),
+            Some(libc::ESRCH) => Err(exited()),
+            _ => Err(e),
+        };
+    }
+
+    // SAFETY: the fd was just created and isn't owned by anything else
+    Ok(unsafe { OwnedFd::from_raw_fd(ret as RawFd) })
+}
+
+/// Open file descriptors of the process along with their `/proc/<pid>/fd` link targets.
+///
+/// Fails with [`ErrorKind::NotFound`] if the process has already exited, the pid may refer to
+/// another process then.
+fn fds(fd: BorrowedFd, pid: Pid) -> Result<Vec<(RawFd, PathBuf)>> {
+    let is_exited = || {
+        let mut fds = [PollFd::new(&fd, PollFlags::IN)];
+        poll(&mut fds, 0).map(|x| x != 0)
+    };
+
+    if is_exited()? {
+        return Err(exited());
+    }
+
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(format!("/proc/{}/fd", pid.as_raw_nonzero()))? {
+        let entry = entry?;
+        let Some(target) = entry.file_name().to_str().and_then(|x| x.parse().ok()) else {
+            continue;
+        };
+
+        match fs::read_link(entry.path()) {
+            Ok(path) => entries.push((target, path)),
+            // closed meanwhile
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    // the listing may belong to another process reusing the pid
+    if is_exited()? {
+        return Err(exited());
+    }
+
+    entries.sort_unstable_by_key(|x| x.0);
+    Ok(entries)
+}
+
+/// Open a pidfd of an unreaped child, its pid can't be reused until it's reaped.
+///
+/// Fails with `ECHILD` if the pid refers to another process since the child was reaped.
+fn open_child(id: u32) -> Result<(OwnedFd, Pid)> {
+    let pid = Pid::from_raw(id as i32).ok_or(ErrorKind::InvalidData)?;
+    let fd = match pidfd_open(pid, PidfdFlags::empty()) {
+        Ok(fd) => fd,
+        Err(e) if e.raw_os_error() == libc::ESRCH => return Err(exited()),
+        Err(e) => return Err(e.into()),
+    };
+
+    // only children can be waited
+    let options = WaitidOptions::EXITED | WaitidOptions::NOHANG | WaitidOptions::NOWAIT;
+    waitid(WaitId::PidFd(fd.as_fd()), options)?;
+
+    Ok((fd, pid))
+}
+
+/// Pid of the process a pidfd refers to, read from its fdinfo.
+///
+/// Fails with [`ErrorKind::InvalidInput`] if `fd` is not a pidfd, and with
+/// [`ErrorKind::NotFound`] if the process has already been reaped.
+fn pidfd_pid(fd: BorrowedFd) -> Result<Pid> {
+    let fdinfo = fs::read_to_string(format!("/proc/self/fdinfo/{}", fd.as_raw_fd()))?;
+    let pid = fdinfo
+        .lines()
+        .find_map(|x| x.strip_prefix("Pid:"))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "not a pidfd"))?;
+
+    match pid.trim().parse::<i32>() {
+        Ok(-1) => Err(exited()),
+        Ok(0) => Err(Error::new(
+            ErrorKind::Unsupported,
+            "the process is outside of the pid namespace",
+        )),
+        Ok(pid) => Pid::from_raw(pid).ok_or_else(|| ErrorKind::InvalidData.into()),
+        Err(_) => Err(ErrorKind::InvalidData.into()),
+    }
+}
+
+/// Collect state change of a child process through `waitid(P_PIDFD)`.
+///
+/// Fails with [`ErrorKind::InvalidInput`] if the process is not a child of the caller.
+fn wait_status(fd: BorrowedFd, options: WaitidOptions) -> Result<Option<WaitStatus>> {
+    match waitid(WaitId::PidFd(fd), options | WaitidOptions::NOHANG) {
+        Ok(status) => Ok(status.map(WaitStatus::from_waitid)),
+        Err(e) if e.raw_os_error() == libc::ECHILD => Err(not_child()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Fails with [`ErrorKind::InvalidInput`] if the process is not a child of the caller, without
+/// collecting anything
+fn check_child(fd: BorrowedFd) -> Result<()> {
+    let options = WaitidOptions::EXITED | WaitidOptions::NOWAIT;
+
+    wait_status(fd, options).map(|_| ())
+}
+
+fn not_child() -> Error {
+    Error::new(
+        ErrorKind::InvalidInput,
+        "the process is not a child of the caller",
+    )
+}
+
+/// Wait until the process is reaped, the pidfd reports `POLLHUP` then, returns whether it was
+fn wait_reaped(fd: BorrowedFd, timeout: i32) -> Result<bool> {
+    // POLLHUP is reported even if not requested, unlike POLLIN of the exit
+    let mut fds = [PollFd::new(&fd, PollFlags::empty())];
+
+    Ok(poll(&mut fds, timeout)? != 0)
+}
+
+/// Treat missing exit information as `None` for backends which can work without it.
+fn optional_exit_info(result: Result<ExitInfo>) -> Result<Option<ExitInfo>> {
+    match result {
+        Ok(info) => Ok(Some(info)),
+        Err(e) if matches!(e.kind(), ErrorKind::Unsupported | ErrorKind::WouldBlock) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
 
 #[derive(Debug)]
 pub(crate) struct PidFdBackend;
 
 impl Backend for PidFdBackend {
-    fn waitpid(&self, pid: Pid, timeout: Option<Duration>) -> Result<()> {
+    fn waitpid(&self, pid: Pid, timeout: Option<Duration>) -> Result<Option<ExitInfo>> {
         let fd = pidfd_open(pid, PidfdFlags::empty())?;
         let timeout = match timeout {
             Some(dur) => dur.as_millis().try_into().unwrap_or(i32::MAX),
@@ -29,17 +291,23 @@ impl Backend for PidFdBackend {
         };
 
         let mut fds = [PollFd::new(&fd, PollFlags::IN)];
-
-        match poll(&mut fds, timeout)? {
-            0 => Err(Error::from(ErrorKind::TimedOut)),
-            _ => Ok(()),
+        if poll(&mut fds, timeout)? == 0 {
+            return Err(Error::from(ErrorKind::TimedOut));
         }
+
+        // recorded once the process is reaped, which may never happen, so it's only reported
+        // if the parent was quick enough
+        optional_exit_info(exit_info(fd.as_fd(), pid))
     }
 }
 
 #[cfg(feature = "async")]
 impl super::AsyncBackend for PidFdBackend {
-    async fn waitpid(&self, pid: Pid) -> Result<()> {
-        AsyncPidFd::new(pid)?.await
+    async fn waitpid(&self, pid: Pid) -> Result<Option<ExitInfo>> {
+        let pidfd = AsyncPidFd::new(pid)?;
+        pidfd.wait().await?;
+
+        // see the sync version
+        optional_exit_info(pidfd.exit_info())
     }
 }
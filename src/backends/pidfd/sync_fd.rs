@@ -1,21 +1,29 @@
 use std::{
     io::{Error, ErrorKind, Result},
-    os::fd::OwnedFd,
-    time::Duration,
+    os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd, RawFd},
+    path::PathBuf,
+    process::Child,
+    time::{Duration, Instant},
 };
 
 use rustix::{
     event::{poll, PollFd, PollFlags},
-    process::{pidfd_open, Pid, PidfdFlags},
+    process::{pidfd_open, Pid, PidfdFlags, Signal, WaitidOptions},
 };
 
-struct PidFdInner(OwnedFd);
+use super::Termination;
+use crate::{ExitInfo, WaitStatus};
+
+struct PidFdInner {
+    fd: OwnedFd,
+    pid: Pid,
+}
 
 impl PidFdInner {
     fn new(pid: Pid) -> Result<Self> {
-        pidfd_open(pid, PidfdFlags::empty())
-            .map(Self)
-            .map_err(Into::into)
+        let fd = pidfd_open(pid, PidfdFlags::empty())?;
+
+        Ok(Self { fd, pid })
     }
 
     fn waitpid(&self, timeout: Option<Duration>) -> Result<()> {
@@ -24,7 +32,7 @@ impl PidFdInner {
             None => -1, // infinity
         };
 
-        let mut fds = [PollFd::new(&self.0, PollFlags::IN)];
+        let mut fds = [PollFd::new(&self.fd, PollFlags::IN)];
         match poll(&mut fds, timeout)? {
             0 => Err(Error::from(ErrorKind::TimedOut)),
             _ => Ok(()),
@@ -48,6 +56,11 @@ impl PidFd {
         PidFdInner::new(pid).map(Self)
     }
 
+    /// `fd` must be a pidfd of `pid`
+    pub(super) fn from_parts(fd: OwnedFd, pid: Pid) -> Result<Self> {
+        Ok(Self(PidFdInner { fd, pid }))
+    }
+
     #[inline]
     pub fn wait(&self, timeout: Option<Duration>) -> Result<()> {
         self.0.waitpid(timeout)
@@ -57,4 +70,168 @@ impl PidFd {
     pub fn is_exited(&self) -> Result<bool> {
         self.0.is_exited()
     }
+
+    /// Send `signal` to the process, with a custom `info` like `rt_sigqueueinfo` if given.
+    ///
+    /// Unlike `kill` it never reaches another process reusing the pid. Fails with
+    /// [`ErrorKind::NotFound`] if the process has already exited.
+    #[inline]
+    pub fn send_signal(&self, signal: Signal, info: Option<&libc::siginfo_t>) -> Result<()> {
+        super::send_signal(self.0.fd.as_fd(), signal, info)
+    }
+
+    /// Send the signals of `policy` in turn until the process exits, each one is followed by
+    /// its grace period.
+    ///
+    /// A usual policy is `SIGTERM` with a long grace period, then `SIGKILL` with a short one.
+    ///
+    /// Fails with [`ErrorKind::TimedOut`] if the process survived every step.
+    pub fn terminate(&self, policy: &[(Signal, Duration)]) -> Result<Termination> {
+        let start = Instant::now();
+        let mut step = None;
+
+        for (i, &(signal, grace)) in policy.iter().enumerate() {
+            match self.send_signal(signal, None) {
+                Ok(()) => step = Some(i),
+                // exited during the previous grace period
+                Err(e) if e.kind() == ErrorKind::NotFound => break,
+                Err(e) => return Err(e),
+            }
+
+            match self.wait(Some(grace)) {
+                Ok(()) => break,
+                Err(e) if e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        match self.is_exited()? {
+            true => Ok(Termination::new(policy, step, start)),
+            false => Err(ErrorKind::TimedOut.into()),
+        }
+    }
+
+    /// Duplicate the file descriptor `target` of the process into the caller, requires ptrace
+    /// access to it.
+    ///
+    /// Fails with [`ErrorKind::PermissionDenied`] without that access, and with
+    /// [`ErrorKind::InvalidInput`] if `target` isn't open in the process.
+    #[inline]
+    pub fn get_fd(&self, target: RawFd) -> Result<OwnedFd> {
+        super::get_fd(self.0.fd.as_fd(), target)
+    }
+
+    /// Open file descriptors of the process and what they refer to, for picking one to
+    /// [`get_fd`](Self::get_fd).
+    #[inline]
+    pub fn fds(&self) -> Result<Vec<(RawFd, PathBuf)>> {
+        super::fds(self.0.fd.as_fd(), self.0.pid)
+    }
+
+    /// Exit status of the process, works for non-child processes too.
+    ///
+    /// Requires Linux 6.15+, fails with [`ErrorKind::Unsupported`] on older kernels
+    /// and with [`ErrorKind::WouldBlock`] if the process is still running or has not been reaped yet.
+    #[inline]
+    pub fn exit_info(&self) -> Result<ExitInfo> {
+        super::exit_info(self.0.fd.as_fd(), self.0.pid)
+    }
+
+    /// Wait for the process to be reaped and query its exit status, see
+    /// [`exit_info`](Self::exit_info).
+    ///
+    /// The parent may never reap it, children of the caller must be reaped by it meanwhile,
+    /// through [`wait_status`](Self::wait_status) for instance. Fails with
+    /// [`ErrorKind::TimedOut`] if the process isn't reaped within `timeout`.
+    pub fn wait_exit_info(&self, timeout: Option<Duration>) -> Result<ExitInfo> {
+        let timeout = match timeout {
+            Some(dur) => dur.as_millis().try_into().unwrap_or(i32::MAX),
+            None => -1, // infinity
+        };
+
+        match super::wait_reaped(self.0.fd.as_fd(), timeout)? {
+            true => self.exit_info(),
+            false => Err(ErrorKind::TimedOut.into()),
+        }
+    }
+
+    /// Collect a pending exit, stop or continue of a child process without blocking.
+    ///
+    /// Exited children are reaped. Fails with [`ErrorKind::InvalidInput`] if the process is not
+    /// a child of the caller.
+    #[inline]
+    pub fn try_wait(&self) -> Result<Option<WaitStatus>> {
+        let options = WaitidOptions::EXITED | WaitidOptions::STOPPED | WaitidOptions::CONTINUED;
+
+        super::wait_status(self.0.fd.as_fd(), options)
+    }
+
+    /// Wait for a child process to exit and reap it.
+    ///
+    /// Fails with [`ErrorKind::InvalidInput`] right away if the process is not a child of the
+    /// caller.
+    pub fn wait_status(&self, timeout: Option<Duration>) -> Result<WaitStatus> {
+        super::check_child(self.0.fd.as_fd())?;
+        self.0.waitpid(timeout)?;
+
+        super::wait_status(self.0.fd.as_fd(), WaitidOptions::EXITED)?
+            .ok_or_else(|| ErrorKind::WouldBlock.into())
+    }
+}
+
+impl AsFd for PidFd {
+    #[inline]
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.fd.as_fd()
+    }
+}
+
+impl AsRawFd for PidFd {
+    #[inline]
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.fd.as_raw_fd()
+    }
+}
+
+/// Fails with [`ErrorKind::InvalidInput`] if `fd` is not a pidfd, `fd` is handed back along
+/// with the error.
+impl TryFrom<OwnedFd> for PidFd {
+    type Error = (OwnedFd, Error);
+
+    fn try_from(fd: OwnedFd) -> std::result::Result<Self, Self::Error> {
+        match super::pidfd_pid(fd.as_fd()) {
+            Ok(pid) => Ok(Self(PidFdInner { fd, pid })),
+            Err(e) => Err((fd, e)),
+        }
+    }
+}
+
+impl From<PidFd> for OwnedFd {
+    #[inline]
+    fn from(pidfd: PidFd) -> Self {
+        pidfd.0.fd
+    }
+}
+
+/// Fails with [`ErrorKind::NotFound`] or `ECHILD` if the child has already been reaped.
+impl TryFrom<&Child> for PidFd {
+    type Error = Error;
+
+    fn try_from(child: &Child) -> Result<Self> {
+        let (fd, pid) = super::open_child(child.id())?;
+
+        Ok(Self(PidFdInner { fd, pid }))
+    }
+}
+
+/// Fails with [`ErrorKind::NotFound`] or `ECHILD` if the child has already been reaped.
+#[cfg(feature = "async")]
+impl TryFrom<&tokio::process::Child> for PidFd {
+    type Error = Error;
+
+    fn try_from(child: &tokio::process::Child) -> Result<Self> {
+        let (fd, pid) = super::open_child(child.id().ok_or_else(super::exited)?)?;
+
+        Ok(Self(PidFdInner { fd, pid }))
+    }
 }
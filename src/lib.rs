@@ -1,4 +1,5 @@
 mod backends;
+mod status;
 mod utils;
 
 use std::{
@@ -6,13 +7,22 @@ use std::{
     time::Duration,
 };
 
-pub use rustix::process::Pid;
+pub use rustix::process::{Pid, Signal};
 
+#[cfg(feature = "netlink")]
+pub use crate::backends::netlink;
 use crate::backends::*;
-pub use crate::{backends::pidfd, utils::process_exists};
+pub use crate::{
+    backends::pidfd,
+    status::{ExitInfo, WaitStatus},
+    utils::process_exists,
+};
 
+/// Wait for a process to exit.
+///
+/// Returns the [`ExitInfo`] when the chosen backend is able to observe it.
 #[allow(unreachable_code)] // while netlink feature disabled
-pub fn waitpid(pid: u32, timeout: Option<Duration>) -> Result<()> {
+pub fn waitpid(pid: u32, timeout: Option<Duration>) -> Result<Option<ExitInfo>> {
     let pid = Pid::from_raw(pid as i32).ok_or(ErrorKind::InvalidInput)?;
 
     // 1. try pidfd
@@ -25,14 +35,15 @@ pub fn waitpid(pid: u32, timeout: Option<Duration>) -> Result<()> {
 
     // 2. try netlink
     #[cfg(feature = "netlink")]
-    netlink::NetlinkBackend::new()?.waitpid(pid, timeout)?;
+    return netlink::NetlinkBackend::shared()?.waitpid(pid, timeout);
 
-    Ok(())
+    Ok(None)
 }
 
+/// Async version of [`waitpid`].
 #[cfg(feature = "async")]
 #[allow(unreachable_code)]
-pub async fn waitpid_async(pid: u32) -> Result<()> {
+pub async fn waitpid_async(pid: u32) -> Result<Option<ExitInfo>> {
     use backends::AsyncBackend;
 
     let pid = Pid::from_raw(pid as i32).ok_or(ErrorKind::InvalidInput)?;
@@ -47,9 +58,9 @@ pub async fn waitpid_async(pid: u32) -> Result<()> {
 
     // 2. try netlink
     #[cfg(feature = "async-netlink")]
-    netlink::AsyncNetlinkBackend::new()?.waitpid(pid).await?;
+    return netlink::AsyncNetlinkBackend::shared()?.waitpid(pid).await;
 
-    Ok(())
+    Ok(None)
 }
 
 #[cfg(not(target_os = "linux"))]
@@ -0,0 +1,108 @@
+use rustix::process::{Pid, WaitidStatus};
+
+/// State change of a child process, as reported by `waitid(2)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitStatus {
+    /// Exited normally with the given exit code.
+    Exited(i32),
+    /// Terminated by a signal, and whether a core dump was produced.
+    Signaled(i32, bool),
+    /// Stopped by the given signal.
+    Stopped(i32),
+    /// Resumed by `SIGCONT`.
+    Continued,
+}
+
+impl WaitStatus {
+    /// Decode a raw wait status as returned by `waitpid(2)`.
+    pub fn from_raw(status: i32) -> Self {
+        if libc::WIFEXITED(status) {
+            Self::Exited(libc::WEXITSTATUS(status))
+        } else if libc::WIFSIGNALED(status) {
+            Self::Signaled(libc::WTERMSIG(status), libc::WCOREDUMP(status))
+        } else if libc::WIFSTOPPED(status) {
+            Self::Stopped(libc::WSTOPSIG(status))
+        } else {
+            Self::Continued
+        }
+    }
+
+    pub(crate) fn from_waitid(status: WaitidStatus) -> Self {
+        if let Some(code) = status.exit_status() {
+            Self::Exited(code as i32)
+        } else if let Some(signal) = status.terminating_signal() {
+            Self::Signaled(signal as i32, status.dumped())
+        } else if let Some(signal) = status.stopping_signal().or(status.trapping_signal()) {
+            Self::Stopped(signal as i32)
+        } else {
+            Self::Continued
+        }
+    }
+}
+
+/// How a process terminated.
+///
+/// The netlink backend fills in everything the kernel reports with the exit
+/// event, other backends only what they are able to observe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExitInfo {
+    pub(crate) pid: Pid,
+    pub(crate) status: i32,
+    pub(crate) exit_signal: Option<i32>,
+    pub(crate) parent_pid: Option<Pid>,
+    pub(crate) timestamp_ns: Option<u64>,
+}
+
+impl ExitInfo {
+    /// PID(TGID) of the exited process.
+    #[inline]
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// Raw wait status, encoded like the one returned by `waitpid(2)`.
+    #[inline]
+    pub fn raw_status(&self) -> i32 {
+        self.status
+    }
+
+    /// Decoded [`raw_status`](Self::raw_status).
+    #[inline]
+    pub fn status(&self) -> WaitStatus {
+        WaitStatus::from_raw(self.status)
+    }
+
+    /// Exit code passed to `exit(2)`, `None` if the process was killed by a signal.
+    #[inline]
+    pub fn code(&self) -> Option<i32> {
+        libc::WIFEXITED(self.status).then(|| libc::WEXITSTATUS(self.status))
+    }
+
+    /// Signal that terminated the process, `None` if it exited normally.
+    #[inline]
+    pub fn signal(&self) -> Option<i32> {
+        libc::WIFSIGNALED(self.status).then(|| libc::WTERMSIG(self.status))
+    }
+
+    #[inline]
+    pub fn core_dumped(&self) -> bool {
+        libc::WIFSIGNALED(self.status) && libc::WCOREDUMP(self.status)
+    }
+
+    /// Signal sent to the parent on exit, usually `SIGCHLD`.
+    #[inline]
+    pub fn exit_signal(&self) -> Option<i32> {
+        self.exit_signal
+    }
+
+    #[inline]
+    pub fn parent_pid(&self) -> Option<Pid> {
+        self.parent_pid
+    }
+
+    /// Nanoseconds since system boot when the process exited.
+    #[inline]
+    pub fn timestamp_ns(&self) -> Option<u64> {
+        self.timestamp_ns
+    }
+}
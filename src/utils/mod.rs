@@ -1,4 +1,6 @@
 pub(crate) mod incomplete_array;
+#[cfg(feature = "netlink")]
+pub(crate) mod procfs;
 
 use std::io::Error;
 
@@ -0,0 +1,130 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs,
+    io::{ErrorKind, Result},
+    os::unix::ffi::OsStringExt,
+    path::PathBuf,
+};
+
+use rustix::process::Pid;
+
+use super::process_exists;
+
+/// State and PPID fields of `/proc/<pid>/stat`
+fn read_stat(pid: Pid) -> Result<(u8, i32)> {
+    parse_stat(&fs::read(format!("/proc/{}/stat", pid.as_raw_nonzero()))?)
+}
+
+fn parse_stat(stat: &[u8]) -> Result<(u8, i32)> {
+    // comm may contain anything, fields after it are separated by single spaces
+    let rest = stat
+        .iter()
+        .rposition(|&c| c == b')')
+        .map(|i| &stat[i + 1..])
+        .ok_or(ErrorKind::InvalidData)?;
+    let mut fields = rest.split(|&c| c == b' ').filter(|x| !x.is_empty());
+
+    let state = fields.next().and_then(|x| x.first().copied());
+    let ppid = fields
+        .next()
+        .and_then(|x| std::str::from_utf8(x).ok())
+        .and_then(|x| x.parse().ok());
+
+    state.zip(ppid).ok_or(ErrorKind::InvalidData.into())
+}
+
+/// Whether the process exists and is neither a zombie nor dead
+#[must_use]
+pub(crate) fn is_running(pid: Pid) -> bool {
+    match read_stat(pid) {
+        // the leader is a zombie until all threads are gone
+        Ok((b'Z', _)) => thread_group_running(pid).unwrap_or(false),
+        Ok((state, _)) => state != b'X',
+        Err(e) if e.kind() == ErrorKind::NotFound => false,
+        // procfs is unavailable
+        Err(_) => process_exists(pid),
+    }
+}
+
+/// Whether any thread of the group is neither a zombie nor dead
+///
+/// A leader exited through `pthread_exit` stays a zombie as long as other threads run,
+/// so its own state says nothing about the group.
+pub(crate) fn thread_group_running(tgid: Pid) -> Result<bool> {
+    let task = format!("/proc/{}/task", tgid.as_raw_nonzero());
+
+    let entries = match fs::read_dir(&task) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e),
+    };
+
+    for entry in entries {
+        let tid = entry?.file_name();
+        let Some(tid) = tid.to_str() else {
+            continue;
+        };
+
+        // exited threads other than the leader are released at any time
+        match fs::read(format!("{task}/{tid}/stat")) {
+            Ok(stat) if !matches!(parse_stat(&stat)?.0, b'Z' | b'X') => return Ok(true),
+            Ok(_) => (),
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(false)
+}
+
+/// `root` and all of its current descendants
+pub(crate) fn process_tree(root: Pid) -> Result<HashSet<Pid>> {
+    let mut children: HashMap<i32, Vec<Pid>> = HashMap::new();
+
+    for entry in fs::read_dir("/proc")? {
+        let name = entry?.file_name();
+        let Some(pid) = name
+            .to_str()
+            .and_then(|x| x.parse().ok())
+            .and_then(Pid::from_raw)
+        else {
+            continue; // not a process
+        };
+
+        // the process may exit at any time
+        if let Ok((_, ppid)) = read_stat(pid) {
+            children.entry(ppid).or_default().push(pid);
+        }
+    }
+
+    let mut tree = HashSet::from([root]);
+    let mut pending = vec![root];
+    while let Some(pid) = pending.pop() {
+        for &child in children
+            .get(&pid.as_raw_nonzero().get())
+            .into_iter()
+            .flatten()
+        {
+            if tree.insert(child) {
+                pending.push(child);
+            }
+        }
+    }
+
+    Ok(tree)
+}
+
+pub(crate) fn exe(pid: Pid) -> Result<PathBuf> {
+    fs::read_link(format!("/proc/{}/exe", pid.as_raw_nonzero()))
+}
+
+pub(crate) fn cmdline(pid: Pid) -> Result<Vec<OsString>> {
+    let cmdline = fs::read(format!("/proc/{}/cmdline", pid.as_raw_nonzero()))?;
+
+    Ok(cmdline
+        .split(|&c| c == 0)
+        .filter(|x| !x.is_empty())
+        .map(|x| OsString::from_vec(x.to_vec()))
+        .collect())
+}